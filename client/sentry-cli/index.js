@@ -1,6 +1,10 @@
 #!/usr/bin/env node
 
+const http = require("http");
+
 const BASE = "http://127.0.0.1:7373";
+// stop and reload are only served here, to root
+const SOCKET = process.env.SENTRY_SOCKET || "/run/sentinel/sentry.sock";
 
 const args = process.argv.slice(2);
 
@@ -10,7 +14,8 @@ Sentinel CLI
 
 Usage:
   sentry-cli status
-  sentry-cli stop
+  sentry-cli stop            (as root)
+  sentry-cli reload          (as root)
   sentry-cli logout
   sentry-cli info --name <name>
   sentry-cli info --reg <reg>
@@ -38,6 +43,30 @@ async function request(path, method = "GET", body = null) {
   }
 }
 
+function control(path) {
+  return new Promise((resolve) => {
+    const req = http.request(
+      { socketPath: SOCKET, path, method: "POST" },
+      (res) => {
+        let text = "";
+        res.on("data", (chunk) => (text += chunk));
+        res.on("end", () => {
+          console.log(text);
+          resolve();
+        });
+      },
+    );
+
+    req.on("error", (err) => {
+      console.error(`Failed to connect to the sentry control socket ${SOCKET}`);
+      console.error(err.message);
+      process.exit(1);
+    });
+
+    req.end();
+  });
+}
+
 async function main() {
   switch (args[0]) {
     case "status":
@@ -45,7 +74,11 @@ async function main() {
       break;
 
     case "stop":
-      await request("/stop", "POST");
+      await control("/stop");
+      break;
+
+    case "reload":
+      await control("/admin/reload");
       break;
 
     case "logout":
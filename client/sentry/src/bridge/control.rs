@@ -0,0 +1,97 @@
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sentinel_protocol::local::PeerCred;
+use sentinel_protocol::{Message, StopAttempt};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::bridge::main::AppState;
+use crate::queue::unix_time;
+
+// Only root, whatever user the sentry itself runs as.
+fn privileged(uid: Option<u32>) -> bool {
+    uid == Some(0)
+}
+
+// Tells sentineld, through the offline queue if need be.
+pub async fn report_stop(server_tx: &mpsc::Sender<Message>, attempt: StopAttempt) {
+    println!(
+        "[CONTROL] Stop attempt via {} {} (uid {:?}, pid {:?})",
+        attempt.via,
+        if attempt.allowed {
+            "allowed"
+        } else {
+            "refused"
+        },
+        attempt.uid,
+        attempt.pid
+    );
+
+    let _ = server_tx.send(attempt.telemetry(unix_time())).await;
+}
+
+// Lets only root through to the control socket routes.
+pub async fn require_privileged(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerCred>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if privileged(peer.uid) {
+        return next.run(req).await;
+    }
+
+    if req.uri().path() == "/stop" {
+        report_stop(
+            &state.server_tx,
+            StopAttempt {
+                via: "socket".into(),
+                allowed: false,
+                uid: peer.uid,
+                pid: peer.pid,
+            },
+        )
+        .await;
+    }
+
+    (
+        StatusCode::FORBIDDEN,
+        "Only root may use the control socket",
+    )
+        .into_response()
+}
+
+// SIGTERM and SIGINT only reach us from root or our own user, so they are
+// allowed stops, but still reported.
+pub async fn watch_stop_signals(
+    server_tx: mpsc::Sender<Message>,
+    shutdown_tx: broadcast::Sender<()>,
+) -> Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = int.recv() => {}
+    }
+
+    report_stop(
+        &server_tx,
+        StopAttempt {
+            via: "signal".into(),
+            allowed: true,
+            uid: None,
+            pid: None,
+        },
+    )
+    .await;
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
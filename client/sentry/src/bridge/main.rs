@@ -1,26 +1,34 @@
 use anyhow::Result;
 use axum::{
-    http::Method,
+    middleware,
     routing::{get, post},
     Router,
 };
+use sentinel_protocol::local::{self, PeerCred};
+use sentinel_protocol::Message;
 use serde::Deserialize;
 use std::sync::Arc;
 use tokio::{
     net::TcpListener,
     sync::{broadcast, mpsc},
 };
-use tower_http::cors::{Any, CorsLayer};
 
+use crate::bridge::control;
 use crate::bridge::protocols::*;
-use crate::{config::SharedConfig, user::SharedUser};
+use crate::config::SharedConfig;
+use crate::failover::SharedRoot;
+use crate::policy::SharedPolicy;
+use crate::reload::Reloader;
+use crate::user::SharedUser;
 
 #[derive(Clone)]
 pub struct AppState {
+    pub reloader: Arc<Reloader>,
     pub shutdown_tx: broadcast::Sender<()>,
     pub user: SharedUser,
-    pub config: SharedConfig,
-    pub server_tx: mpsc::Sender<String>,
+    pub root: SharedRoot,
+    pub policy: SharedPolicy,
+    pub server_tx: mpsc::Sender<Message>,
 }
 
 #[derive(Deserialize)]
@@ -30,46 +38,88 @@ pub struct InfoRequest {
 }
 
 pub async fn run_http_server(
-    shutdown_tx: broadcast::Sender<()>,
-    mut shutdown: broadcast::Receiver<()>,
-    user: SharedUser,
     config: SharedConfig,
-    server_tx: mpsc::Sender<String>,
+    state: AppState,
+    mut shutdown: broadcast::Receiver<()>,
 ) -> Result<()> {
-    let state = Arc::new(AppState {
-        shutdown_tx,
-        user,
-        config,
-        server_tx,
-    });
-
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers(Any);
+    let (listen, socket, socket_mode) = {
+        let cfg = config.lock().await;
+        (
+            cfg.api.listen.clone(),
+            cfg.api.socket.clone(),
+            cfg.api.socket_mode,
+        )
+    };
+    let state = Arc::new(state);
 
+    // No CORS: nothing in a browser has any business here, and a web page
+    // should not be able to change who is logged in.
     let app = Router::new()
         .route("/info", post(info))
         .route("/logout", post(logout))
         .route("/status", get(status))
-        .route("/stop", post(stop))
-        .with_state(state)
-        .layer(cors);
+        .route("/stop", post(refuse_stop))
+        .with_state(state.clone());
 
-    let listener = TcpListener::bind("127.0.0.1:7373").await?;
+    let listener = TcpListener::bind(&listen).await?;
 
-    println!("HTTP control server ready at 127.0.0.1:7373");
+    println!("HTTP control server ready at {}", listen);
 
-    tokio::select! {
-
-        res = axum::serve(listener, app) => {
-            res?;
+    // Without the socket the monitor still runs; it can then only be stopped
+    // by a signal, which takes root (or the sentry's user) anyway.
+    let bound = if socket.is_empty() {
+        None
+    } else {
+        match local::bind(&socket, socket_mode) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                println!(
+                    "[CONTROL] Control socket disabled, stop by signal only: {:#}",
+                    e
+                );
+                None
+            }
         }
+    };
+
+    let control = bound.map(|listener| {
+        let app = Router::new()
+            .route("/info", post(info))
+            .route("/logout", post(logout))
+            .route("/status", get(status))
+            .route("/stop", post(stop))
+            .route("/admin/reload", post(reload))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                control::require_privileged,
+            ))
+            .with_state(state);
+
+        println!("HTTP control socket ready at {}", socket);
+
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<PeerCred>();
+
+            if let Err(e) = axum::serve(listener, app).await {
+                println!("[CONTROL] Control socket failed: {}", e);
+            }
+        })
+    });
+
+    let result = tokio::select! {
+
+        res = axum::serve(listener, app) => res.map_err(Into::into),
 
         _ = shutdown.recv() => {
             println!("HTTP server shutting down");
+            Ok(())
         }
+    };
+
+    if let Some(control) = control {
+        control.abort();
+        let _ = std::fs::remove_file(&socket);
     }
 
-    Ok(())
+    result
 }
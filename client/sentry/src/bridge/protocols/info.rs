@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use axum::{extract::State, Json};
+use sentinel_protocol::{Info, Message};
 
 use crate::bridge::main::{AppState, InfoRequest};
 
@@ -18,12 +19,10 @@ pub async fn info(
         u.reg = reg;
     }
 
-    let version = state.config.lock().await.version.clone();
-
-    let info = format!(
-        "INFO user {}\n{{\"name\":\"{}\",\"regno\":\"{}\"}}\n",
-        version, u.name, u.reg
-    );
+    let info = Message::Info(Info {
+        name: u.name.clone(),
+        regno: u.reg.clone(),
+    });
 
     if let Err(e) = state.server_tx.send(info).await {
         eprintln!("server channel closed: {}", e);
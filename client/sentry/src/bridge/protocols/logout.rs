@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use axum::extract::State;
+use sentinel_protocol::{Info, Message};
 
 use crate::bridge::main::AppState;
 
@@ -10,12 +11,10 @@ pub async fn logout(State(state): State<Arc<AppState>>) -> Result<String, String
     u.name = "unknown".to_string();
     u.reg = "unknown".to_string();
 
-    let version = state.config.lock().await.version.clone();
-
-    let info = format!(
-        "INFO user {}\n{{\"name\":\"{}\",\"regno\":\"{}\"}}\n",
-        version, u.name, u.reg
-    );
+    let info = Message::Info(Info {
+        name: u.name.clone(),
+        regno: u.reg.clone(),
+    });
 
     state
         .server_tx
@@ -1,9 +1,11 @@
 pub mod info;
 pub mod logout;
+pub mod reload;
 pub mod status;
 pub mod stop;
 
 pub use info::info;
 pub use logout::logout;
+pub use reload::reload;
 pub use status::status;
-pub use stop::stop;
+pub use stop::{refuse_stop, stop};
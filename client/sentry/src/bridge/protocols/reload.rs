@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use sentinel_protocol::reload::{log_report, ReloadReport};
+
+use crate::bridge::main::AppState;
+
+// Same as SIGHUP: re-reads the config file and applies what it can live.
+pub async fn reload(State(state): State<Arc<AppState>>) -> Result<Json<ReloadReport>, String> {
+    match state.reloader.reload().await {
+        Ok(report) => {
+            log_report("[CONFIG]", &report);
+            Ok(Json(report))
+        }
+        Err(e) => {
+            println!("[CONFIG] Reload rejected: {}", e);
+            Err(format!("Reload rejected: {}", e))
+        }
+    }
+}
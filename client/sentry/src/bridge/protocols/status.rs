@@ -1,3 +1,23 @@
-pub async fn status() -> &'static str {
-    "Sentry running"
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use crate::bridge::main::AppState;
+use crate::failover::RootStatus;
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub status: &'static str,
+    pub root: RootStatus,
+    // revision of the policy in force, 0 before the first one
+    pub policy: u64,
+}
+
+pub async fn status(State(state): State<Arc<AppState>>) -> Json<StatusResponse> {
+    Json(StatusResponse {
+        status: "Sentry running",
+        root: state.root.lock().await.clone(),
+        policy: state.policy.revision(),
+    })
 }
@@ -1,11 +1,52 @@
 use std::sync::Arc;
 
-use axum::extract::State;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+};
+use sentinel_protocol::local::PeerCred;
+use sentinel_protocol::StopAttempt;
 
+use crate::bridge::control::report_stop;
 use crate::bridge::main::AppState;
 
-pub async fn stop(State(state): State<Arc<AppState>>) -> &'static str {
+// Only reachable on the control socket, past the root check.
+pub async fn stop(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerCred>,
+) -> &'static str {
+    report_stop(
+        &state.server_tx,
+        StopAttempt {
+            via: "socket".into(),
+            allowed: true,
+            uid: peer.uid,
+            pid: peer.pid,
+        },
+    )
+    .await;
+
     let _ = state.shutdown_tx.send(());
 
     "Stopping sentry"
 }
+
+// Anyone on the machine can reach the TCP listener, so a stop there is
+// refused and reported: it is most likely the student trying to get out.
+pub async fn refuse_stop(State(state): State<Arc<AppState>>) -> (StatusCode, &'static str) {
+    report_stop(
+        &state.server_tx,
+        StopAttempt {
+            via: "http".into(),
+            allowed: false,
+            uid: None,
+            pid: None,
+        },
+    )
+    .await;
+
+    (
+        StatusCode::FORBIDDEN,
+        "Stop is only available on the control socket",
+    )
+}
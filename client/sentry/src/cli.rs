@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Result};
+
+use crate::config::{Endpoint, DEFAULT_PATH};
+
+pub const USAGE: &str = "\
+Usage: sentry [options]
+       sentry init [options] [init options]
+
+Options:
+  -c, --config PATH     config file (default /etc/sentinel/sentry.toml)
+      --no-daemon       stay in the foreground
+  -h, --help            print this help
+
+Init options (prompted for when left out and run from a terminal):
+      --id ID           client id of this machine
+      --server ADDR     root server as ip[:port], repeat in order of preference
+      --discover        find the root server on the LAN instead
+      --kafka ADDR      Kafka brokers
+      --topic NAME      Kafka topic for browser visits
+      --psk SECRET      pre-shared key for the root server
+  -y, --yes             do not prompt, use the flags and current values
+";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<String>,
+    pub no_daemon: bool,
+    pub help: bool,
+    pub init: Option<InitArgs>,
+}
+
+#[derive(Debug, Default)]
+pub struct InitArgs {
+    pub id: Option<String>,
+    pub servers: Vec<Endpoint>,
+    pub discover: bool,
+    pub kafka: Option<String>,
+    pub topic: Option<String>,
+    pub psk: Option<String>,
+    pub yes: bool,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter().peekable();
+
+        if args.peek().map(String::as_str) == Some("init") {
+            args.next();
+            parsed.init = Some(InitArgs::default());
+        }
+
+        while let Some(arg) = args.next() {
+            let mut value =
+                |name: &str| args.next().ok_or_else(|| anyhow!("{} needs a value", name));
+
+            match (arg.as_str(), parsed.init.as_mut()) {
+                ("-c" | "--config", _) => parsed.config = Some(value(&arg)?),
+                ("--no-daemon", _) => parsed.no_daemon = true,
+                ("-h" | "--help", _) => parsed.help = true,
+
+                ("--id", Some(init)) => init.id = Some(value(&arg)?),
+                ("--server", Some(init)) => {
+                    let server = value(&arg)?;
+                    init.servers
+                        .push(server.parse().map_err(|e| anyhow!("--server: {}", e))?);
+                }
+                ("--discover", Some(init)) => init.discover = true,
+                ("--kafka", Some(init)) => init.kafka = Some(value(&arg)?),
+                ("--topic", Some(init)) => init.topic = Some(value(&arg)?),
+                ("--psk", Some(init)) => init.psk = Some(value(&arg)?),
+                ("-y" | "--yes", Some(init)) => init.yes = true,
+
+                (other, _) => return Err(anyhow!("unknown option '{}'\n\n{}", other, USAGE)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn config_path(&self) -> &str {
+        self.config.as_deref().unwrap_or(DEFAULT_PATH)
+    }
+}
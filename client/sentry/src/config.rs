@@ -1,41 +1,466 @@
-use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use sentinel_protocol::local;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, net::SocketAddr, path::Path, sync::Arc};
 use tokio::sync::Mutex;
+use tracing_subscriber::EnvFilter;
 
-#[derive(Debug)]
+// Written by `sentry init`, read on every start unless -c says otherwise.
+pub const DEFAULT_PATH: &str = "/etc/sentinel/sentry.toml";
+
+pub const VERSION: &str = "0.1";
+
+// Mirrors sentry.toml section by section. Only `client.id` has no default.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub client_id: String,
-    pub version: String,
+    pub client: ClientConfig,
+    pub connection: ConnectionConfig,
+    pub daemon: DaemonConfig,
+    pub log: LogConfig,
+    pub queue: QueueConfig,
+    pub kafka: KafkaConfig,
+    pub browser: BrowserConfig,
+    pub api: ApiConfig,
+    pub policy: PolicyConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    // root servers, most preferred first; empty finds one through LAN discovery
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<Endpoint>,
+}
 
-    pub server_ip: String,
-    pub server_port: String,
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub id: String,
+    // secret for sentineld's HMAC challenge, per client or per lab
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub psk: Option<String>,
+}
 
-    pub stdout: String,
-    pub stderr: String,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    // seconds between pings, and of silence before the root is dropped
+    pub heartbeat_interval: u64,
+    pub heartbeat_timeout: u64,
+
+    // failed attempts in a row before moving to the next server
+    pub failover_after: u32,
+    // seconds between checks whether the preferred server is back
+    pub fallback_interval: u64,
+
+    // seconds to wait for discovery announcements
+    pub discovery_timeout: u64,
+    // certificate fingerprint of the first root server discovered over TLS,
+    // the only one trusted from then on
+    pub pin_path: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    pub daemonize: bool,
     pub pid: String,
+}
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
     pub verbose: bool,
+    // tracing filter
+    pub level: String,
+    // where output goes once daemonized
+    pub stdout: String,
+    pub stderr: String,
+}
 
-    pub daemonize: bool,
+// Messages for the root are kept here while it is unreachable, in a
+// directory only root can write to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub path: String,
+    pub max_bytes: u64,
 }
 
-impl Config {
-    pub fn new() -> Config {
-        Config {
-            client_id: "27".to_string(),
-            version: "0.1".to_string(),
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    pub brokers: String,
+    // browser visits go here, "<client id>-browser" when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
 
-            server_ip: "127.0.0.1".to_string(),
-            server_port: "1612".to_string(),
+// Local endpoint the browser extension posts visits to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrowserConfig {
+    pub listen: String,
+}
 
-            stdout: "/tmp/sentry.out".to_string(),
-            stderr: "/tmp/sentry.err".to_string(),
+// Local control API used by sentry-cli. `listen` only takes the student's
+// name and registration number; stop and reload are served on `socket`,
+// to root alone ("" for none, leaving signals as the only way to stop).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub listen: String,
+    pub socket: String,
+    pub socket_mode: u32,
+}
+
+// Last policy received from sentineld, followed until the next one. Kept
+// in a directory only root can write to; a file anyone else could have
+// changed is ignored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    pub ip: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_port() -> u16 {
+    1612
+}
+
+// IPv6 addresses are bracketed, "[fd00::5]:1612", as connect expects.
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ip.contains(':') {
+            write!(f, "[{}]:{}", self.ip, self.port)
+        } else {
+            write!(f, "{}:{}", self.ip, self.port)
+        }
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    // "10.0.0.5", "10.0.0.5:1612", "fd00::5" or "[fd00::5]:1612"
+    fn from_str(s: &str) -> Result<Endpoint> {
+        let (ip, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (ip, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("'{}' is missing a closing ']'", s))?;
+
+            match rest {
+                "" => (ip, None),
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => (ip, Some(port)),
+                    None => return Err(anyhow!("'{}' has text after the address", s)),
+                },
+            }
+        } else if s.matches(':').count() > 1 {
+            (s, None)
+        } else {
+            match s.split_once(':') {
+                Some((ip, port)) => (ip, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow!("'{}' is not a valid port", port))?,
+            None => default_port(),
+        };
+
+        if ip.is_empty() {
+            return Err(anyhow!("'{}' has no address", s));
+        }
+
+        Ok(Endpoint {
+            ip: ip.to_string(),
+            port,
+        })
+    }
+}
+
+// Mutual TLS towards sentineld. `cert` carries this machine's client id as
+// its common name, `server_name` defaults to the root server's address.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub ca: String,
+    pub cert: String,
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            heartbeat_interval: 10,
+            heartbeat_timeout: 30,
+
+            failover_after: 3,
+            fallback_interval: 60,
+
+            discovery_timeout: 3,
+            pin_path: "/var/lib/sentinel/sentry.pin".to_string(),
+        }
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            daemonize: true,
             pid: "/tmp/sentry.pid".to_string(),
+        }
+    }
+}
 
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
             verbose: true,
+            level: "info,rdkafka=off".to_string(),
+            stdout: "/tmp/sentry.out".to_string(),
+            stderr: "/tmp/sentry.err".to_string(),
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            path: "/var/lib/sentinel/sentry.queue".to_string(),
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            brokers: "127.0.0.1:9092".to_string(),
+            topic: None,
+        }
+    }
+}
+
+impl Default for BrowserConfig {
+    fn default() -> Self {
+        BrowserConfig {
+            listen: "127.0.0.1:7777".to_string(),
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            listen: "127.0.0.1:7373".to_string(),
+            socket: "/run/sentinel/sentry.sock".to_string(),
+            socket_mode: 0o600,
+        }
+    }
+}
 
-            daemonize: false,
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        PolicyConfig {
+            path: "/var/lib/sentinel/sentry.policy".to_string(),
         }
     }
 }
 
+impl Config {
+    // None when the file does not exist yet, i.e. on first run.
+    pub fn load(path: &str) -> Result<Option<Config>> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let config = toml::from_str(&text).map_err(|e| anyhow!("{}: {}", path, e))?;
+
+        Ok(Some(config))
+    }
+
+    // Writes the config readable by root only, since it may hold the psk.
+    // The file is created with that mode and renamed into place, so the psk
+    // is never readable on the way; the directory must be root's alone.
+    pub fn save(&self, path: &str) -> Result<()> {
+        if let Some(dir) = Path::new(path).parent() {
+            local::secure_dir(dir, 0o755)?;
+        }
+
+        let text = toml::to_string_pretty(self)?;
+        local::write_atomic(path, text.as_bytes(), 0o600)
+    }
+
+    // Topic browser visits are produced to.
+    pub fn browser_topic(&self) -> String {
+        self.kafka
+            .topic
+            .clone()
+            .unwrap_or_else(|| format!("{}-browser", self.client.id))
+    }
+
+    // Checks what serde cannot. Errors name the offending key the way it is
+    // written in the file.
+    pub fn validate(&self) -> Result<()> {
+        if self.client.id.is_empty() {
+            return Err(anyhow!("client.id: not set, run `sentry init`"));
+        }
+
+        if self.client.id.parse::<usize>().is_err() {
+            return Err(anyhow!("client.id: '{}' is not a number", self.client.id));
+        }
+
+        if self.client.psk.as_deref() == Some("") {
+            return Err(anyhow!("client.psk: secret is empty"));
+        }
+
+        for (i, server) in self.servers.iter().enumerate() {
+            if server.ip.is_empty() {
+                return Err(anyhow!("servers[{}].ip: must not be empty", i));
+            }
+
+            if server.port == 0 {
+                return Err(anyhow!("servers[{}].port: must not be 0", i));
+            }
+        }
+
+        let connection = &self.connection;
+
+        if connection.heartbeat_interval == 0 {
+            return Err(anyhow!("connection.heartbeat_interval: must be at least 1"));
+        }
+
+        if connection.heartbeat_timeout <= connection.heartbeat_interval {
+            return Err(anyhow!(
+                "connection.heartbeat_timeout: must be longer than connection.heartbeat_interval ({})",
+                connection.heartbeat_interval
+            ));
+        }
+
+        for (key, value) in [
+            (
+                "connection.failover_after",
+                connection.failover_after as u64,
+            ),
+            ("connection.fallback_interval", connection.fallback_interval),
+            ("connection.discovery_timeout", connection.discovery_timeout),
+        ] {
+            if value == 0 {
+                return Err(anyhow!("{}: must be at least 1", key));
+            }
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            return Err(anyhow!(
+                "log.level: '{}' is not a valid filter: {}",
+                self.log.level,
+                e
+            ));
+        }
+
+        if self.kafka.brokers.is_empty() {
+            return Err(anyhow!("kafka.brokers: must not be empty"));
+        }
+
+        if self.kafka.topic.as_deref() == Some("") {
+            return Err(anyhow!("kafka.topic: must not be empty"));
+        }
+
+        for (key, addr) in [
+            ("browser.listen", &self.browser.listen),
+            ("api.listen", &self.api.listen),
+        ] {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(anyhow!(
+                    "{}: '{}' is not an address like 127.0.0.1:7373",
+                    key,
+                    addr
+                ));
+            }
+        }
+
+        if self.api.socket_mode > 0o777 {
+            return Err(anyhow!(
+                "api.socket_mode: {:#o} is not a permission mode like 0o600",
+                self.api.socket_mode
+            ));
+        }
+
+        if self.policy.path.is_empty() {
+            return Err(anyhow!("policy.path: must not be empty"));
+        }
+
+        if let Some(tls) = &self.tls {
+            for (key, path) in [
+                ("tls.ca", &tls.ca),
+                ("tls.cert", &tls.cert),
+                ("tls.key", &tls.key),
+            ] {
+                if !Path::new(path).is_file() {
+                    return Err(anyhow!("{}: no such file '{}'", key, path));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub type SharedConfig = Arc<Mutex<Config>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(s: &str) -> (String, u16) {
+        let endpoint: Endpoint = s.parse().unwrap();
+        (endpoint.ip, endpoint.port)
+    }
+
+    #[test]
+    fn parses_addresses_with_and_without_a_port() {
+        assert_eq!(endpoint("10.0.0.5"), ("10.0.0.5".into(), 1612));
+        assert_eq!(endpoint("10.0.0.5:1700"), ("10.0.0.5".into(), 1700));
+        assert_eq!(endpoint("fd00::5"), ("fd00::5".into(), 1612));
+        assert_eq!(endpoint("[fd00::5]"), ("fd00::5".into(), 1612));
+        assert_eq!(endpoint("[fd00::5]:1700"), ("fd00::5".into(), 1700));
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for bad in [
+            ":1612",
+            "10.0.0.5:x",
+            "[fd00::5",
+            "[fd00::5]1612",
+            "[]:1612",
+        ] {
+            assert!(bad.parse::<Endpoint>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn brackets_ipv6_when_displayed() {
+        assert_eq!(
+            "10.0.0.5".parse::<Endpoint>().unwrap().to_string(),
+            "10.0.0.5:1612"
+        );
+        assert_eq!(
+            "fd00::5".parse::<Endpoint>().unwrap().to_string(),
+            "[fd00::5]:1612"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::Result;
+use sentinel_protocol::discovery::{Discovery, DISCOVERY_GROUP, DISCOVERY_PORT, MAX_DATAGRAM};
+use sentinel_protocol::{auth, local};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+use crate::config::Endpoint;
+
+// A root server that answered our probe.
+#[derive(Clone, Debug)]
+pub struct Found {
+    pub ip: String,
+    pub port: u16,
+    pub fingerprint: Option<String>,
+    pub tls: bool,
+}
+
+// Certificate fingerprint of the root server we first connected to over
+// TLS, if any. A pin file anyone else could have written is ignored.
+pub fn load_pin(path: &str) -> Option<String> {
+    let pin = match local::read_trusted(path) {
+        Ok(pin) => pin?,
+        Err(e) => {
+            println!("[DISCOVERY] Ignoring pin: {:#}", e);
+            return None;
+        }
+    };
+    let pin = pin.trim();
+
+    (!pin.is_empty()).then(|| pin.to_string())
+}
+
+pub fn save_pin(path: &str, fingerprint: &str) {
+    let saved = Path::new(path)
+        .parent()
+        .map_or(Ok(()), |dir| local::secure_dir(dir, 0o700))
+        .and_then(|_| local::write_atomic(path, fingerprint.as_bytes(), 0o600));
+
+    if let Err(e) = saved {
+        println!("[DISCOVERY] Could not save pin: {:#}", e);
+    }
+}
+
+// Probes the LAN and returns the first root server that answers. With a
+// pre-shared key the server must prove it holds it; once a certificate is
+// pinned, announcements without that fingerprint are ignored.
+pub async fn discover(
+    client_id: &str,
+    psk: Option<&str>,
+    pinned: Option<&str>,
+    wait: Duration,
+) -> Result<Option<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+
+    let nonce = auth::nonce();
+    let probe = Discovery::Probe {
+        nonce: nonce.clone(),
+        client_id: psk.map(|_| client_id.to_string()),
+    }
+    .to_bytes();
+
+    let targets = [
+        SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT)),
+        SocketAddr::from((DISCOVERY_GROUP, DISCOVERY_PORT)),
+    ];
+
+    let mut sent = false;
+    for target in targets {
+        match socket.send_to(&probe, target).await {
+            Ok(_) => sent = true,
+            Err(e) => println!("[DISCOVERY] Probe to {} failed: {}", target, e),
+        }
+    }
+
+    if !sent {
+        return Ok(None);
+    }
+
+    let deadline = Instant::now() + wait;
+    let mut buf = [0u8; MAX_DATAGRAM];
+
+    while let Ok(result) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = result?;
+
+        let Some(Discovery::Announce {
+            nonce: echoed,
+            ip,
+            port,
+            fingerprint,
+            tls,
+            proof,
+        }) = Discovery::from_bytes(&buf[..len])
+        else {
+            continue;
+        };
+
+        if echoed != nonce {
+            continue;
+        }
+
+        if let Some(secret) = psk {
+            let addr = Endpoint {
+                ip: ip.clone(),
+                port,
+            }
+            .to_string();
+            let proven = proof.is_some_and(|proof| {
+                auth::verify_announce(secret, &nonce, client_id, &addr, &proof)
+            });
+
+            if !proven {
+                println!(
+                    "[DISCOVERY] Ignoring {} ({}): no proof of the pre-shared key",
+                    ip, from
+                );
+                continue;
+            }
+        }
+
+        if pinned.is_some_and(|pin| fingerprint.as_deref() != Some(pin)) {
+            println!(
+                "[DISCOVERY] Ignoring {} ({}): fingerprint does not match pin",
+                ip, from
+            );
+            continue;
+        }
+
+        return Ok(Some(Found {
+            ip,
+            port,
+            fingerprint,
+            tls,
+        }));
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,145 @@
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::config::Endpoint;
+
+// Walks the configured root servers in order of preference. After
+// `failover_after` failed attempts in a row it moves on to the next one,
+// wrapping around at the end of the list.
+pub struct Failover {
+    endpoints: Vec<Endpoint>,
+    active: usize,
+    failures: u32,
+    failover_after: u32,
+}
+
+impl Failover {
+    pub fn new(endpoints: Vec<Endpoint>, failover_after: u32) -> Failover {
+        Failover {
+            endpoints,
+            active: 0,
+            failures: 0,
+            failover_after: failover_after.max(1),
+        }
+    }
+
+    // Takes a reloaded server list. Starts over from the preferred server
+    // only if the list itself changed.
+    pub fn update(&mut self, endpoints: &[Endpoint], failover_after: u32) {
+        if self.endpoints != endpoints {
+            *self = Failover::new(endpoints.to_vec(), failover_after);
+        }
+
+        self.failover_after = failover_after.max(1);
+    }
+
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+
+    // None when no servers are configured and discovery is used instead.
+    pub fn current(&self) -> Option<&Endpoint> {
+        self.endpoints.get(self.active)
+    }
+
+    pub fn preferred(&self) -> Option<&Endpoint> {
+        self.endpoints.first()
+    }
+
+    pub fn on_preferred(&self) -> bool {
+        self.active == 0
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn connected(&mut self) {
+        self.failures = 0;
+    }
+
+    // Records a failed attempt. Returns the endpoint switched to, if any.
+    pub fn failed(&mut self) -> Option<&Endpoint> {
+        self.failures += 1;
+
+        if self.failures < self.failover_after || self.endpoints.len() < 2 {
+            return None;
+        }
+
+        self.active = (self.active + 1) % self.endpoints.len();
+        self.failures = 0;
+        self.current()
+    }
+
+    pub fn fall_back(&mut self) {
+        self.active = 0;
+        self.failures = 0;
+    }
+}
+
+// What the status API reports about the link to the root server.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RootStatus {
+    pub endpoint: Option<String>,
+    pub connected: bool,
+    pub preferred: bool,
+    pub discovered: bool,
+    pub failures: u32,
+}
+
+pub type SharedRoot = Arc<Mutex<RootStatus>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints(ips: &[&str]) -> Vec<Endpoint> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn moves_on_after_enough_failures_and_wraps_around() {
+        let mut failover = Failover::new(endpoints(&["10.0.0.5", "10.0.0.6"]), 2);
+
+        assert!(failover.failed().is_none());
+        assert_eq!(failover.failed().unwrap().ip, "10.0.0.6");
+        assert!(!failover.on_preferred());
+
+        failover.failed();
+        assert_eq!(failover.failed().unwrap().ip, "10.0.0.5");
+        assert!(failover.on_preferred());
+    }
+
+    #[test]
+    fn a_connection_resets_the_count() {
+        let mut failover = Failover::new(endpoints(&["10.0.0.5", "10.0.0.6"]), 2);
+
+        failover.failed();
+        failover.connected();
+
+        assert!(failover.failed().is_none());
+        assert!(failover.on_preferred());
+    }
+
+    #[test]
+    fn a_single_server_is_never_left() {
+        let mut failover = Failover::new(endpoints(&["10.0.0.5"]), 1);
+
+        assert!(failover.failed().is_none());
+        assert!(failover.on_preferred());
+    }
+
+    #[test]
+    fn update_starts_over_only_when_the_list_changes() {
+        let list = endpoints(&["10.0.0.5", "10.0.0.6"]);
+        let mut failover = Failover::new(list.clone(), 1);
+        failover.failed();
+
+        failover.update(&list, 1);
+        assert!(!failover.on_preferred());
+
+        failover.update(&endpoints(&["10.0.0.7", "10.0.0.6"]), 1);
+        assert_eq!(failover.current().unwrap().ip, "10.0.0.7");
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::cli::InitArgs;
+use crate::config::{Config, Endpoint};
+
+// Prints `question [default]: ` and returns the answer, or the default
+// when the line is left empty.
+fn ask(question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+
+    let answer = line.trim();
+    Ok(if answer.is_empty() {
+        default.to_string()
+    } else {
+        answer.to_string()
+    })
+}
+
+fn ask_servers(current: &[Endpoint]) -> Result<Vec<Endpoint>> {
+    let current = current
+        .iter()
+        .map(Endpoint::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    loop {
+        let answer = ask(
+            "Root servers, comma separated (\"discover\" to find one on the LAN)",
+            if current.is_empty() {
+                "discover"
+            } else {
+                &current
+            },
+        )?;
+
+        if answer == "discover" {
+            return Ok(Vec::new());
+        }
+
+        match answer
+            .split(',')
+            .map(|s| s.trim().parse())
+            .collect::<Result<Vec<Endpoint>>>()
+        {
+            Ok(servers) => return Ok(servers),
+            Err(e) => println!("  {}", e),
+        }
+    }
+}
+
+// `sentry init`: creates or updates the config at `path`. Flags are applied
+// first; from a terminal the rest is asked for, with current values as the
+// defaults.
+pub fn run(path: &str, args: &InitArgs) -> Result<Config> {
+    let mut config = Config::load(path)?.unwrap_or_default();
+
+    if let Some(id) = &args.id {
+        config.client.id = id.clone();
+    }
+
+    if args.discover {
+        config.servers.clear();
+    } else if !args.servers.is_empty() {
+        config.servers = args.servers.clone();
+    }
+
+    if let Some(kafka) = &args.kafka {
+        config.kafka.brokers = kafka.clone();
+    }
+
+    if let Some(topic) = &args.topic {
+        config.kafka.topic = Some(topic.clone());
+    }
+
+    if let Some(psk) = &args.psk {
+        config.client.psk = Some(psk.clone());
+    }
+
+    if !args.yes && io::stdin().is_terminal() {
+        println!("Setting up sentry, saving to {}", path);
+
+        if args.id.is_none() {
+            config.client.id = ask("Client id of this machine", &config.client.id)?;
+        }
+
+        if args.servers.is_empty() && !args.discover {
+            config.servers = ask_servers(&config.servers)?;
+        }
+
+        if args.kafka.is_none() {
+            config.kafka.brokers = ask("Kafka brokers", &config.kafka.brokers)?;
+        }
+    }
+
+    config.validate()?;
+    config.save(path)?;
+
+    println!("Saved {}", path);
+    Ok(config)
+}
+
+// First start without a config: run the interactive setup when someone is
+// at the terminal, otherwise there is nothing sensible to start with.
+pub fn first_run(path: &str) -> Result<Option<Config>> {
+    if !io::stdin().is_terminal() {
+        return Ok(None);
+    }
+
+    println!("No config found at {}", path);
+    run(path, &InitArgs::default()).map(Some)
+}
@@ -1,34 +1,73 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use daemonize::Daemonize;
-use std::{fs::File, sync::Arc};
+use sentinel_protocol::reload::watch_sighup;
+use sentinel_protocol::{Action, Message};
+use std::{fs::File, sync::Arc, time::Duration};
 use tokio::{
     runtime::Builder,
     sync::{broadcast, mpsc, Mutex},
 };
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 use crate::{
-    bridge::main::run_http_server,
+    bridge::{
+        control::watch_stop_signals,
+        main::{run_http_server, AppState},
+    },
+    cli::{Args, USAGE},
     config::{Config, SharedConfig},
+    failover::{RootStatus, SharedRoot},
     monitor::init::start_monitor,
-    tcp::root_server_task,
+    policy::{ActivePolicy, SharedPolicy},
+    reload::Reloader,
+    tcp::{root_server_task, Channels},
     user::{SharedUser, UserInfo},
 };
 
 mod bridge;
+mod cli;
 mod config;
+mod discovery;
+mod failover;
+mod init;
 mod monitor;
+mod policy;
+mod queue;
+mod reload;
 mod tcp;
 mod user;
 
 fn main() -> Result<()> {
-    let config = Config::new();
-    let verbose = config.verbose;
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::new("info,rdkafka=off"))
+    let args = Args::parse(std::env::args().skip(1))?;
+    let path = args.config_path();
+
+    if args.help {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+
+    if let Some(init) = &args.init {
+        init::run(path, init)?;
+        return Ok(());
+    }
+
+    let config = match Config::load(path)? {
+        Some(config) => config,
+        None => init::first_run(path)?
+            .ok_or_else(|| anyhow!("no config at {}, run `sentry init` first", path))?,
+    };
+
+    config.validate().map_err(|e| anyhow!("{}: {}", path, e))?;
+
+    let verbose = config.log.verbose;
+    let (filter, log) =
+        tracing_subscriber::reload::Layer::new(EnvFilter::try_new(&config.log.level)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
         .init();
 
-    if config.daemonize {
+    if config.daemon.daemonize && !args.no_daemon {
         daemonize(&config)?;
     }
 
@@ -37,17 +76,18 @@ fn main() -> Result<()> {
     }
 
     let config: SharedConfig = Arc::new(Mutex::new(config));
+    let reloader = Arc::new(Reloader::new(path, config.clone(), log));
 
     let runtime = Builder::new_multi_thread().enable_all().build()?;
-    runtime.block_on(async_main(config.clone()))
+    runtime.block_on(async_main(config.clone(), reloader))
 }
 
 fn daemonize(config: &Config) -> Result<()> {
-    let stdout = File::create(&config.stdout)?;
-    let stderr = File::create(&config.stderr)?;
+    let stdout = File::create(&config.log.stdout)?;
+    let stderr = File::create(&config.log.stderr)?;
 
     let daemon = Daemonize::new()
-        .pid_file(&config.pid)
+        .pid_file(&config.daemon.pid)
         .stdout(stdout)
         .stderr(stderr);
 
@@ -55,41 +95,78 @@ fn daemonize(config: &Config) -> Result<()> {
     Ok(())
 }
 
-async fn async_main(config: SharedConfig) -> Result<()> {
-    let verbose = config.lock().await.verbose.clone();
+async fn async_main(config: SharedConfig, reloader: Arc<Reloader>) -> Result<()> {
+    let verbose = config.lock().await.log.verbose;
 
     if verbose {
         println!("[SENTRY] Sentry daemon starting");
     }
 
     let user: SharedUser = Arc::new(Mutex::new(UserInfo::new()));
+    let root: SharedRoot = Arc::new(Mutex::new(RootStatus::default()));
+    let policy: SharedPolicy = Arc::new(ActivePolicy::open(&config.lock().await.policy.path));
 
-    let (network_tx, network_rx) = mpsc::channel::<String>(100);
-    let (server_tx, server_rx) = mpsc::channel::<String>(100);
+    let (network_tx, network_rx) = mpsc::channel::<Action>(100);
+    let (server_tx, server_rx) = mpsc::channel::<Message>(100);
 
     let (shutdown_tx, _) = broadcast::channel::<()>(1);
 
     let shutdown_root = shutdown_tx.subscribe();
     let shutdown_http = shutdown_tx.subscribe();
 
-    tokio::spawn(root_server_task(
-        network_tx,
-        server_rx,
+    let sighup_reloader = reloader.clone();
+    tokio::spawn(async move {
+        if let Err(e) = watch_sighup("[CONFIG]", || sighup_reloader.reload()).await {
+            eprintln!("SIGHUP handler error: {:?}", e);
+        }
+    });
+
+    let (signal_server_tx, signal_shutdown_tx) = (server_tx.clone(), shutdown_tx.clone());
+    tokio::spawn(async move {
+        if let Err(e) = watch_stop_signals(signal_server_tx, signal_shutdown_tx).await {
+            eprintln!("Signal handler error: {:?}", e);
+        }
+    });
+
+    let root_task = tokio::spawn(root_server_task(
+        Channels {
+            network_tx,
+            server_rx,
+        },
         user.clone(),
         config.clone(),
+        reloader.subscribe(),
+        root.clone(),
+        policy.clone(),
         shutdown_root,
     ));
 
-    tokio::spawn(start_monitor(network_rx, shutdown_tx.subscribe()));
+    tokio::spawn(start_monitor(
+        config.clone(),
+        reloader.subscribe(),
+        policy.subscribe(),
+        network_rx,
+        server_tx.clone(),
+        shutdown_tx.subscribe(),
+    ));
 
     run_http_server(
-        shutdown_tx,
+        config,
+        AppState {
+            reloader,
+            shutdown_tx,
+            user: user.clone(),
+            root,
+            policy,
+            server_tx,
+        },
         shutdown_http,
-        user.clone(),
-        config.clone(),
-        server_tx,
     )
     .await?;
 
+    // give the root connection a moment to pass on the last messages,
+    // such as the report of this stop
+    let _ = tokio::time::timeout(Duration::from_secs(3), root_task).await;
+
     Ok(())
 }
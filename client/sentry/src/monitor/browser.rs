@@ -3,16 +3,22 @@ use axum::{routing::post, Json, Router};
 use rdkafka::config::RDKafkaLogLevel;
 use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
 use rdkafka::ClientConfig;
+use sentinel_protocol::{policy::UrlAction, Policy};
 use serde::Deserialize;
 use serde_json::json;
 use std::io::Write;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::{fs, path::Path};
 use tokio::time::sleep;
-use tokio::{net::TcpListener, sync::broadcast};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+};
 use tower_http::cors::{Any, CorsLayer};
 
+use crate::config::SharedConfig;
+
 // TODO:
 // need to share this to kafka
 #[derive(Deserialize)]
@@ -22,7 +28,10 @@ struct Visit {
 }
 
 struct AppState {
-    producer: FutureProducer,
+    // swapped out when a reload changes the brokers
+    producer: RwLock<FutureProducer>,
+    config: SharedConfig,
+    policy: watch::Receiver<Policy>,
 }
 
 async fn create_kafka_producer(kafka_ip: &str) -> FutureProducer {
@@ -96,34 +105,93 @@ fn _enforce_policy() {
 }
 
 async fn log(State(state): State<Arc<AppState>>, Json(data): Json<Visit>) {
+    let (enabled, topic, flagged) = {
+        let policy = state.policy.borrow();
+        (
+            policy.monitors.browser,
+            policy.kafka_topic.clone(),
+            policy
+                .url_rule(&data.url)
+                .filter(|rule| rule.action == UrlAction::Flag)
+                .map(|rule| rule.host.clone()),
+        )
+    };
+
+    // switched off by policy
+    if !enabled {
+        return;
+    }
+
     println!("Visited: {}", data.url);
 
-    let payload = json!({
+    let mut payload = json!({
         "url": data.url,
         "timestamp": data.timestamp
-    })
-    .to_string();
+    });
+
+    if let Some(host) = flagged {
+        println!("[BROWSER] Flagged visit to {}", data.url);
+        payload["flagged"] = json!(host);
+    }
+
+    let payload = payload.to_string();
 
-    let _ = state
-        .producer
+    let topic = match topic {
+        Some(topic) => topic,
+        None => state.config.lock().await.browser_topic(),
+    };
+    let producer = state.producer.read().unwrap().clone();
+
+    let _ = producer
         .send(
-            // Topic: [Client_id]-log-browser
-            FutureRecord::to("27-browser")
-                .payload(&payload)
-                .key("visit"),
+            // Topic: [Client_id]-browser unless configured or set by policy
+            FutureRecord::to(&topic).payload(&payload).key("visit"),
             Duration::from_secs(0),
         )
         .await;
 }
 
-pub async fn browser_monitor(_shutdown_tx: broadcast::Receiver<()>) {
-    // TODO:
-    // need to hook it with config
+// Reconnects to Kafka whenever a config reload changes the brokers.
+async fn follow_brokers(
+    state: Arc<AppState>,
+    mut brokers: String,
+    mut reloads: watch::Receiver<()>,
+) {
+    while reloads.changed().await.is_ok() {
+        let latest = state.config.lock().await.kafka.brokers.clone();
+
+        if latest == brokers {
+            continue;
+        }
+
+        println!("[KAFKA]: Brokers changed to {}, reconnecting", latest);
+        let producer = create_kafka_producer(&latest).await;
+
+        *state.producer.write().unwrap() = producer;
+        brokers = latest;
+    }
+}
+
+pub async fn browser_monitor(
+    config: SharedConfig,
+    reloads: watch::Receiver<()>,
+    policy: watch::Receiver<Policy>,
+    _shutdown_tx: broadcast::Receiver<()>,
+) {
+    let (kafka_ip, listen) = {
+        let cfg = config.lock().await;
+        (cfg.kafka.brokers.clone(), cfg.browser.listen.clone())
+    };
+
+    let producer = create_kafka_producer(&kafka_ip).await;
 
-    let kafka_ip = "127.0.0.1:9092";
-    let producer = create_kafka_producer(kafka_ip).await;
+    let state = Arc::new(AppState {
+        producer: RwLock::new(producer),
+        config,
+        policy,
+    });
 
-    let state = Arc::new(AppState { producer });
+    tokio::spawn(follow_brokers(state.clone(), kafka_ip, reloads));
 
     // before starting the system, need to enforce the
     // policy to the firefox, for this testing we need to do in ubuntu
@@ -146,7 +214,7 @@ pub async fn browser_monitor(_shutdown_tx: broadcast::Receiver<()>) {
     // TODO:
     // need to make it secure. either by some kinda key or integrity check
     // but anyways its used to logs, well see.
-    let listener = TcpListener::bind("127.0.0.1:7777").await.unwrap();
+    let listener = TcpListener::bind(&listen).await.unwrap();
 
     axum::serve(listener, app.into_make_service())
         .await
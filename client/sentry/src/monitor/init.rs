@@ -1,13 +1,39 @@
-use tokio::sync::{broadcast, mpsc};
+use sentinel_protocol::{Action, Capability, Message, Policy};
+use tokio::sync::{broadcast, mpsc, watch};
 
+use crate::config::SharedConfig;
 use crate::monitor::{browser::browser_monitor, network::network_task};
 
 pub async fn start_monitor(
-    network_rx: mpsc::Receiver<String>,
+    config: SharedConfig,
+    reloads: watch::Receiver<()>,
+    policy: watch::Receiver<Policy>,
+    network_rx: mpsc::Receiver<Action>,
+    server_tx: mpsc::Sender<Message>,
     shutdown_tx: broadcast::Receiver<()>,
 ) {
     println!("[SENTRY] Starting Monitor!");
 
-    tokio::spawn(browser_monitor(shutdown_tx.resubscribe()));
-    tokio::spawn(network_task(network_rx, shutdown_tx.resubscribe()));
+    tokio::spawn(browser_monitor(
+        config,
+        reloads,
+        policy.clone(),
+        shutdown_tx.resubscribe(),
+    ));
+    tokio::spawn(network_task(
+        network_rx,
+        server_tx,
+        policy,
+        shutdown_tx.resubscribe(),
+    ));
+}
+
+// What this sentry offers sentineld during the handshake, one entry per
+// monitor started above, plus following pushed policies.
+pub fn capabilities() -> Vec<Capability> {
+    vec![
+        Capability::BrowserMonitor,
+        Capability::Firewall,
+        Capability::Policy,
+    ]
 }
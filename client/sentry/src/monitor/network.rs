@@ -1,6 +1,8 @@
 use anyhow::Result;
+use sentinel_protocol::policy::FirewallAction;
+use sentinel_protocol::{Action, ActionResult, Message, Policy};
 use std::net::Ipv4Addr;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
 
 // TODO:
 // need to have three async functions
@@ -8,31 +10,85 @@ use tokio::sync::{broadcast, mpsc};
 // 2. DNS proxy server firewall
 // 3. eBPF kernel level firewall (need to decide weather to add)
 pub async fn network_task(
-    mut rx: mpsc::Receiver<String>,
+    mut rx: mpsc::Receiver<Action>,
+    server_tx: mpsc::Sender<Message>,
+    mut policy: watch::Receiver<Policy>,
     _shutdown_tx: broadcast::Receiver<()>,
 ) -> Result<()> {
     println!("[NETWORK] Firewall ready");
 
-    while let Some(message) = rx.recv().await {
-        println!("[NETWORK] {}", message.trim());
+    apply_rules(&policy.borrow_and_update());
 
-        if let Some(ip_str) = parse_block_ip(&message) {
-            let ip: u32 = ip_str.parse::<Ipv4Addr>()?.into();
+    loop {
+        tokio::select! {
 
-            println!("[NETWORK] Blocking {}", ip_str);
+            Ok(()) = policy.changed() => apply_rules(&policy.borrow_and_update()),
 
-            let _ = ip;
+            action = rx.recv() => {
+                let Some(action) = action else { break };
+
+                println!("[NETWORK] {}", action);
+
+                let result = handle_action(&action, &policy.borrow());
+
+                if let Err(e) = server_tx.send(Message::ActionResult(result)).await {
+                    println!("[NETWORK] server channel closed {}", e);
+                }
+            }
         }
     }
 
     Ok(())
 }
 
-fn parse_block_ip(message: &str) -> Option<&str> {
-    let parts: Vec<&str> = message.trim().split_whitespace().collect();
+// Firewall rules from the policy replace the previous policy's as a whole.
+fn apply_rules(policy: &Policy) {
+    if !policy.monitors.network {
+        println!("[NETWORK] Disabled by policy {}", policy.revision);
+        return;
+    }
+
+    for rule in &policy.firewall_rules {
+        match rule.action {
+            FirewallAction::Block => println!("[NETWORK] Policy blocks {}", rule.ip),
+            FirewallAction::Allow => println!("[NETWORK] Policy allows {}", rule.ip),
+        }
+    }
+}
+
+fn handle_action(action: &Action, policy: &Policy) -> ActionResult {
+    if !policy.monitors.network {
+        return ActionResult::new(action, false, "network monitor is disabled by policy");
+    }
+
+    let Some(ip_str) = parse_block_ip(action) else {
+        return ActionResult::new(action, false, format!("unknown command {}", action.command));
+    };
+
+    let ip: u32 = match ip_str.parse::<Ipv4Addr>() {
+        Ok(ip) => ip.into(),
+        Err(e) => return ActionResult::new(action, false, format!("invalid ip {}: {}", ip_str, e)),
+    };
+
+    // an allow rule in the policy outranks a one-off block
+    if policy
+        .firewall_rules
+        .iter()
+        .any(|rule| rule.action == FirewallAction::Allow && rule.ip == ip_str)
+    {
+        return ActionResult::new(action, false, format!("{} is allowed by policy", ip_str));
+    }
+
+    println!("[NETWORK] Blocking {}", ip_str);
+
+    let _ = ip;
+
+    ActionResult::new(action, true, format!("blocked {}", ip_str))
+}
 
-    if parts.len() == 4 && parts[0] == "ACTION" && parts[1] == "network" && parts[2] == "BLOCK" {
-        return Some(parts[3]);
+fn parse_block_ip(action: &Action) -> Option<&str> {
+    if action.command == "BLOCK" && action.args.len() == 1 {
+        return Some(&action.args[0]);
     }
 
     None
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use sentinel_protocol::local;
+use sentinel_protocol::Policy;
+use std::{path::Path, sync::Arc};
+use tokio::sync::watch;
+
+// The policy in force, as last sent by sentineld. It is kept on disk so a
+// machine that restarts while the root is unreachable still follows it.
+// Monitors subscribe to hear about a new one.
+pub struct ActivePolicy {
+    path: String,
+    tx: watch::Sender<Policy>,
+}
+
+pub type SharedPolicy = Arc<ActivePolicy>;
+
+impl ActivePolicy {
+    // Starts from the saved policy, or the empty one if there is none or
+    // it cannot be trusted.
+    pub fn open(path: &str) -> ActivePolicy {
+        let policy = match local::read_trusted(path) {
+            Ok(Some(text)) => match serde_json::from_str::<Policy>(&text) {
+                Ok(policy) => {
+                    println!("[POLICY] Loaded revision {}", policy.revision);
+                    policy
+                }
+                Err(e) => {
+                    println!("[POLICY] Ignoring {}: {}", path, e);
+                    Policy::default()
+                }
+            },
+            Ok(None) => Policy::default(),
+            Err(e) => {
+                println!("[POLICY] Ignoring saved policy: {:#}", e);
+                Policy::default()
+            }
+        };
+
+        ActivePolicy {
+            path: path.to_string(),
+            tx: watch::channel(policy).0,
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.tx.borrow().revision
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Policy> {
+        self.tx.subscribe()
+    }
+
+    // An invalid policy is refused and the current one stays in force.
+    pub fn apply(&self, policy: Policy) -> Result<()> {
+        policy.validate()?;
+
+        if let Err(e) = self.save(&policy) {
+            println!("[POLICY] Could not save: {:#}", e);
+        }
+
+        println!("[POLICY] Applied revision {}", policy.revision);
+        self.tx.send_replace(policy);
+
+        Ok(())
+    }
+
+    // Into a directory only root can change, replacing the old file in one
+    // step.
+    fn save(&self, policy: &Policy) -> Result<()> {
+        if let Some(dir) = Path::new(&self.path).parent() {
+            local::secure_dir(dir, 0o700)?;
+        }
+
+        local::write_atomic(&self.path, serde_json::to_string(policy)?.as_bytes(), 0o600)
+    }
+}
@@ -0,0 +1,368 @@
+use sentinel_protocol::{local, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    queued_at: u64,
+    message: Message,
+}
+
+// Messages for the root that could not be delivered, kept on disk as JSON
+// lines so they survive a restart. The sequence counter lives next to it in
+// `<path>.seq`, written whenever the file is rewritten, and never goes back,
+// even after the queue drains; the last sequence number the root
+// acknowledged is in `<path>.acked`. Whatever is in these files is replayed
+// to the root as this machine's own, so they are only trusted in a
+// directory and with an owner no student could have used.
+pub struct Outbox {
+    path: String,
+    // false when the directory is not root's alone; the queue then lives
+    // in memory only
+    disk: bool,
+    max_bytes: u64,
+    entries: VecDeque<(Entry, u64)>,
+    bytes: u64,
+    next_seq: u64,
+    acked: u64,
+    // size of the acknowledged lines still in the file
+    acked_bytes: u64,
+}
+
+// Acknowledged lines are only cut out of the file once the queue drains or
+// this many bytes of them have built up, not on every acknowledgement.
+const COMPACT_BYTES: u64 = 64 * 1024;
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Outbox {
+    pub fn open(path: &str, max_bytes: u64) -> Outbox {
+        let mut outbox = Outbox {
+            path: path.to_string(),
+            disk: true,
+            max_bytes,
+            entries: VecDeque::new(),
+            bytes: 0,
+            next_seq: 1,
+            acked: 0,
+            acked_bytes: 0,
+        };
+
+        if let Some(dir) = Path::new(path).parent() {
+            if let Err(e) = local::secure_dir(dir, 0o700) {
+                println!("[QUEUE] {:#}, keeping the queue in memory only", e);
+                outbox.disk = false;
+                return outbox;
+            }
+        }
+
+        if let Some(seq) = outbox.read(&outbox.seq_path()) {
+            outbox.next_seq = seq.trim().parse().unwrap_or(1);
+        }
+
+        if let Some(acked) = outbox.read(&outbox.acked_path()) {
+            outbox.acked = acked.trim().parse().unwrap_or(0);
+        }
+
+        let queued = outbox.read(path).unwrap_or_default();
+
+        for line in queued.lines() {
+            // a half written last line after a crash is simply dropped
+            if let Ok(entry) = serde_json::from_str::<Entry>(line) {
+                let size = line.len() as u64 + 1;
+                outbox.next_seq = outbox.next_seq.max(entry.seq + 1);
+
+                // acknowledged before the file was last compacted
+                if entry.seq <= outbox.acked {
+                    outbox.acked_bytes += size;
+                    continue;
+                }
+
+                outbox.bytes += size;
+                outbox.entries.push_back((entry, size));
+            }
+        }
+
+        if !outbox.entries.is_empty() {
+            println!("[QUEUE] {} queued messages loaded", outbox.entries.len());
+        }
+
+        outbox
+    }
+
+    // None for a missing file and for one that is not trusted; the latter
+    // is replaced on the next rewrite.
+    fn read(&self, path: &str) -> Option<String> {
+        match local::read_trusted(path) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("[QUEUE] Ignoring {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn seq_path(&self) -> String {
+        format!("{}.seq", self.path)
+    }
+
+    fn acked_path(&self) -> String {
+        format!("{}.acked", self.path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn push(&mut self, message: Message) {
+        let entry = Entry {
+            seq: self.next_seq,
+            queued_at: unix_time(),
+            message,
+        };
+
+        let Ok(line) = serde_json::to_string(&entry) else {
+            return;
+        };
+
+        // the line itself carries the sequence number, so .seq only has
+        // to be current once lines leave the file
+        self.next_seq += 1;
+
+        let size = line.len() as u64 + 1;
+        self.bytes += size;
+        self.entries.push_back((entry, size));
+
+        if self.bytes > self.max_bytes {
+            while self.bytes > self.max_bytes {
+                match self.entries.pop_front() {
+                    Some((_, size)) => self.bytes -= size,
+                    None => break,
+                }
+            }
+
+            println!("[QUEUE] Queue full, dropped oldest messages");
+            self.rewrite();
+            return;
+        }
+
+        if !self.disk {
+            return;
+        }
+
+        let appended =
+            local::append(&self.path, 0o600).and_then(|mut file| writeln!(file, "{}", line));
+
+        if let Err(e) = appended {
+            println!("[QUEUE] Failed writing {}: {}", self.path, e);
+        }
+    }
+
+    // Everything still queued, oldest first, ready to send.
+    pub fn replay(&self) -> Vec<Message> {
+        self.entries
+            .iter()
+            .map(|(entry, _)| Message::Replayed {
+                seq: entry.seq,
+                queued_at: entry.queued_at,
+                message: Box::new(entry.message.clone()),
+            })
+            .collect()
+    }
+
+    // The root has stored everything up to and including `seq`. Only the
+    // acknowledged sequence number is written each time; the file itself
+    // is compacted once per replayed batch, or sooner if it grows.
+    pub fn ack(&mut self, seq: u64) {
+        let before = self.entries.len();
+
+        while let Some((entry, size)) = self.entries.front() {
+            if entry.seq > seq {
+                break;
+            }
+
+            self.bytes -= size;
+            self.acked_bytes += size;
+            self.entries.pop_front();
+        }
+
+        if self.entries.len() == before {
+            return;
+        }
+
+        self.acked = self.acked.max(seq);
+
+        if !self.disk {
+            return;
+        }
+
+        if let Err(e) =
+            local::write_atomic(&self.acked_path(), self.acked.to_string().as_bytes(), 0o600)
+        {
+            println!("[QUEUE] {:#}", e);
+        }
+
+        if self.entries.is_empty() || self.acked_bytes >= COMPACT_BYTES {
+            self.rewrite();
+        }
+    }
+
+    fn rewrite(&mut self) {
+        if !self.disk {
+            return;
+        }
+
+        // first, so the counter is never behind the lines that carried it
+        let seq = self.next_seq.to_string();
+
+        if let Err(e) = local::write_atomic(&self.seq_path(), seq.as_bytes(), 0o600) {
+            println!("[QUEUE] {:#}", e);
+            return;
+        }
+
+        let mut contents = String::new();
+
+        for (entry, _) in &self.entries {
+            if let Ok(line) = serde_json::to_string(entry) {
+                contents.push_str(&line);
+                contents.push('\n');
+            }
+        }
+
+        // write then rename so a crash never leaves a truncated queue
+        match local::write_atomic(&self.path, contents.as_bytes(), 0o600) {
+            Ok(()) => self.acked_bytes = 0,
+            Err(e) => println!("[QUEUE] {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_path(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("sentry-queue-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("queue").to_string_lossy().into_owned()
+    }
+
+    fn seqs(outbox: &Outbox) -> Vec<u64> {
+        outbox
+            .replay()
+            .into_iter()
+            .map(|message| match message {
+                Message::Replayed { seq, .. } => seq,
+                other => panic!("not a replay: {:?}", other),
+            })
+            .collect()
+    }
+
+    fn fill(outbox: &mut Outbox, count: u64) {
+        for seq in 0..count {
+            outbox.push(Message::Ping { seq });
+        }
+    }
+
+    #[test]
+    fn replays_in_order_and_survives_a_restart() {
+        let path = temp_path("order");
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 3);
+
+        assert_eq!(seqs(&outbox), vec![1, 2, 3]);
+        assert_eq!(seqs(&Outbox::open(&path, 1 << 20)), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn ack_drops_everything_up_to_seq() {
+        let path = temp_path("ack");
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 4);
+
+        outbox.ack(2);
+        assert_eq!(seqs(&outbox), vec![3, 4]);
+
+        // an old or repeated ack changes nothing
+        outbox.ack(1);
+        assert_eq!(seqs(&outbox), vec![3, 4]);
+    }
+
+    #[test]
+    fn acked_lines_stay_gone_before_compaction() {
+        let path = temp_path("partial");
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 4);
+        outbox.ack(2);
+
+        // still in the file, but not replayed again after a restart
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+        assert_eq!(seqs(&Outbox::open(&path, 1 << 20)), vec![3, 4]);
+    }
+
+    #[test]
+    fn draining_compacts_the_file() {
+        let path = temp_path("drain");
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 3);
+        outbox.ack(3);
+
+        assert_eq!(outbox.len(), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn sequence_never_goes_back() {
+        let path = temp_path("seq");
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 2);
+        outbox.ack(2);
+
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 1);
+        assert_eq!(seqs(&outbox), vec![3]);
+    }
+
+    #[test]
+    fn a_file_others_can_write_is_not_replayed() {
+        let path = temp_path("untrusted");
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 2);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        assert_eq!(outbox.len(), 0);
+
+        // and is replaced, not appended to, once the queue is written again
+        fill(&mut outbox, 1);
+        outbox.ack(1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest() {
+        let path = temp_path("full");
+        let mut outbox = Outbox::open(&path, 1 << 20);
+        fill(&mut outbox, 1);
+        let line = outbox.bytes;
+
+        let mut outbox = Outbox::open(&path, line * 2);
+        fill(&mut outbox, 2);
+
+        assert_eq!(seqs(&outbox), vec![2, 3]);
+        assert_eq!(seqs(&Outbox::open(&path, line * 2)), vec![2, 3]);
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use sentinel_protocol::reload::ReloadReport;
+use tokio::sync::watch;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::config::{Config, SharedConfig};
+
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+// Keys picked up without a restart, matched as prefixes of dotted key
+// names. apply_live() below must copy exactly these.
+const LIVE: &[&str] = &["log.level", "kafka", "servers", "connection"];
+
+// Re-reads the config file the daemon was started with and applies it to
+// the running one. Tasks that cache settings subscribe to hear about it.
+pub struct Reloader {
+    path: String,
+    config: SharedConfig,
+    log: LogHandle,
+    notify: watch::Sender<()>,
+}
+
+fn apply_live(running: &mut Config, new: &Config) {
+    running.log.level = new.log.level.clone();
+    running.kafka = new.kafka.clone();
+    running.servers = new.servers.clone();
+    running.connection = new.connection.clone();
+}
+
+impl Reloader {
+    pub fn new(path: &str, config: SharedConfig, log: LogHandle) -> Reloader {
+        Reloader {
+            path: path.to_string(),
+            config,
+            log,
+            notify: watch::channel(()).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.notify.subscribe()
+    }
+
+    // An invalid file leaves the running config untouched.
+    pub async fn reload(&self) -> Result<ReloadReport> {
+        let new =
+            Config::load(&self.path)?.ok_or_else(|| anyhow!("{} no longer exists", self.path))?;
+        new.validate()
+            .map_err(|e| anyhow!("{}: {}", self.path, e))?;
+
+        let mut running = self.config.lock().await;
+
+        let report = ReloadReport::compare(&*running, &new, LIVE)?;
+
+        if running.log.level != new.log.level {
+            self.log
+                .reload(EnvFilter::try_new(&new.log.level)?)
+                .map_err(|e| anyhow!("log.level: {}", e))?;
+        }
+
+        apply_live(&mut running, &new);
+        drop(running);
+
+        if !report.applied.is_empty() {
+            self.notify.send_replace(());
+        }
+
+        Ok(report)
+    }
+}
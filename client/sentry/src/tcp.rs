@@ -1,150 +1,602 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, StreamExt};
+use sentinel_protocol::{
+    auth, framed, tls, Action, ActionResult, BoxedStream, Connection, Info, Message, Target,
+    Telemetry, PROTOCOL_VERSIONS,
+};
+use serde_json::json;
 use std::time::Duration;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::{broadcast, mpsc},
-    time::sleep,
+    sync::{broadcast, mpsc, watch},
+    time::{interval_at, sleep, timeout, Instant, Interval},
+};
+
+use crate::{
+    config::{Endpoint, SharedConfig, TlsConfig, VERSION},
+    discovery::{discover, load_pin, save_pin},
+    failover::{Failover, SharedRoot},
+    monitor::init::capabilities,
+    policy::SharedPolicy,
+    queue::{unix_time, Outbox},
+    user::SharedUser,
 };
 
-use crate::{config::SharedConfig, user::SharedUser};
+// Sleeps while offline, moving anything meant for the root into the outbox
+// instead of leaving the senders blocked on a full channel. Returns true if
+// the sentry is shutting down.
+async fn wait_offline(
+    server_rx: &mut mpsc::Receiver<Message>,
+    outbox: &mut Outbox,
+    shutdown: &mut broadcast::Receiver<()>,
+    duration: Duration,
+) -> bool {
+    let deadline = sleep(duration);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => return false,
+
+            _ = shutdown.recv() => {
+                while let Ok(msg) = server_rx.try_recv() {
+                    outbox.push(msg);
+                }
+                return true;
+            }
+
+            Some(msg) = server_rx.recv() => outbox.push(msg),
+        }
+    }
+}
+
+// Wraps the connection in TLS. A discovered server must also present the
+// certificate it announced.
+async fn secure(
+    stream: TcpStream,
+    tls_config: &TlsConfig,
+    ip: &str,
+    expected: Option<&str>,
+) -> Result<BoxedStream> {
+    let connector = tls::connector(&tls_config.ca, &tls_config.cert, &tls_config.key)?;
+    let name = tls::server_name(tls_config.server_name.as_deref().unwrap_or(ip))?;
+
+    let stream = connector.connect(name, stream).await?;
+
+    if let Some(expected) = expected {
+        let presented = tls::peer_fingerprint(stream.get_ref().1.peer_certificates());
+
+        if presented.as_deref() != Some(expected) {
+            return Err(anyhow!(
+                "server certificate does not match its announcement"
+            ));
+        }
+    }
+
+    Ok(Box::new(stream))
+}
+
+async fn handshake(
+    conn: &mut Connection<BoxedStream>,
+    client_id: String,
+    version: String,
+    psk: Option<&str>,
+) -> Result<u32> {
+    conn.send(Message::Hello {
+        client_id: client_id.clone(),
+        version: version.clone(),
+        protocols: PROTOCOL_VERSIONS.to_vec(),
+        capabilities: capabilities(),
+    })
+    .await?;
+
+    loop {
+        match conn.next().await {
+            Some(Ok(Message::HelloAck {
+                protocol,
+                capabilities,
+                ..
+            })) => {
+                println!("[SERVER] Negotiated capabilities: {:?}", capabilities);
+                return Ok(protocol);
+            }
+
+            Some(Ok(Message::Challenge { nonce })) => {
+                let secret = psk.ok_or_else(|| anyhow!("server requires a pre-shared key"))?;
+                let mac = auth::sign(secret, &nonce, &client_id, &version);
+
+                conn.send(Message::Auth { mac }).await?;
+            }
+
+            Some(Ok(Message::Error { message })) => return Err(anyhow!("rejected: {}", message)),
+            Some(Ok(other)) => return Err(anyhow!("unexpected {:?}", other)),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(anyhow!("server closed the connection")),
+        }
+    }
+}
+
+// Periodic report asked for by the policy's telemetry.status_interval.
+async fn status_report(user: &SharedUser, outbox: &Outbox, policy: &SharedPolicy) -> Message {
+    let u = user.lock().await;
+
+    Message::Telemetry(Telemetry {
+        source: "status".to_string(),
+        timestamp: unix_time(),
+        data: json!({
+            "name": u.name,
+            "regno": u.reg,
+            "queued": outbox.len(),
+            "policy": policy.revision(),
+        }),
+    })
+}
+
+// Ticks every `seconds`, or never when it is 0.
+fn status_ticker(seconds: u64) -> Option<Interval> {
+    (seconds > 0).then(|| {
+        let period = Duration::from_secs(seconds);
+        interval_at(Instant::now() + period, period)
+    })
+}
+
+// Next tick of an optional ticker, never resolving without one.
+async fn next_tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Counts a failed attempt against the current server, moving on to the
+// next one once it has failed often enough.
+async fn record_failure(failover: &mut Failover, root: &SharedRoot) {
+    if let Some(next) = failover.failed() {
+        println!("[SERVER] Failing over to {}", next);
+    }
+
+    root.lock().await.failures = failover.failures();
+}
+
+// Whether a plain TCP connection to `addr` succeeds within a few seconds.
+async fn reachable(addr: &str) -> bool {
+    matches!(
+        timeout(Duration::from_secs(3), TcpStream::connect(addr)).await,
+        Ok(Ok(_))
+    )
+}
+
+// Where actions from the root go, and where messages for it come from.
+pub struct Channels {
+    pub network_tx: mpsc::Sender<Action>,
+    pub server_rx: mpsc::Receiver<Message>,
+}
 
 pub async fn root_server_task(
-    network_tx: mpsc::Sender<String>,
-    mut server_rx: mpsc::Receiver<String>,
+    channels: Channels,
     user: SharedUser,
     config: SharedConfig,
+    mut reloads: watch::Receiver<()>,
+    root: SharedRoot,
+    policy: SharedPolicy,
     mut shutdown: broadcast::Receiver<()>,
 ) -> Result<()> {
+    let Channels {
+        network_tx,
+        mut server_rx,
+    } = channels;
+
+    let (mut outbox, mut failover) = {
+        let cfg = config.lock().await;
+        (
+            Outbox::open(&cfg.queue.path, cfg.queue.max_bytes),
+            Failover::new(cfg.servers.clone(), cfg.connection.failover_after),
+        )
+    };
+
     loop {
-        let (ip, port, client_id, version) = {
+        {
+            let cfg = config.lock().await;
+            failover.update(&cfg.servers, cfg.connection.failover_after);
+        }
+
+        let (
+            client_id,
+            version,
+            tls_config,
+            psk,
+            heartbeat_interval,
+            heartbeat_timeout,
+            pin_path,
+            discovery_timeout,
+            fallback_interval,
+        ) = {
             let cfg = config.lock().await;
             (
-                cfg.server_ip.clone(),
-                cfg.server_port.clone(),
-                cfg.client_id.clone(),
-                cfg.version.clone(),
+                cfg.client.id.clone(),
+                VERSION.to_string(),
+                cfg.tls.clone(),
+                cfg.client.psk.clone(),
+                Duration::from_secs(cfg.connection.heartbeat_interval),
+                Duration::from_secs(cfg.connection.heartbeat_timeout),
+                cfg.connection.pin_path.clone(),
+                Duration::from_secs(cfg.connection.discovery_timeout),
+                Duration::from_secs(cfg.connection.fallback_interval),
             )
         };
 
-        let addr = format!("{}:{}", ip, port);
+        let (ip, port, found) = match failover.current() {
+            Some(endpoint) => (endpoint.ip.clone(), endpoint.port, None),
+            None => {
+                let pin = load_pin(&pin_path);
+
+                match discover(
+                    &client_id,
+                    psk.as_deref(),
+                    pin.as_deref(),
+                    discovery_timeout,
+                )
+                .await
+                {
+                    Ok(Some(found)) => {
+                        println!(
+                            "[DISCOVERY] Found root server at {}:{}",
+                            found.ip, found.port
+                        );
+                        (found.ip.clone(), found.port, Some(found))
+                    }
+                    Ok(None) => {
+                        println!("[DISCOVERY] No root server answered");
+                        if wait_offline(
+                            &mut server_rx,
+                            &mut outbox,
+                            &mut shutdown,
+                            Duration::from_secs(5),
+                        )
+                        .await
+                        {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("[DISCOVERY] Discovery failed: {}", e);
+                        if wait_offline(
+                            &mut server_rx,
+                            &mut outbox,
+                            &mut shutdown,
+                            Duration::from_secs(5),
+                        )
+                        .await
+                        {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let addr = Endpoint {
+            ip: ip.clone(),
+            port,
+        }
+        .to_string();
 
         println!("[SERVER] Trying to connect to {}", addr);
 
-        let mut stream = match TcpStream::connect(&addr).await {
+        {
+            let mut status = root.lock().await;
+            status.endpoint = Some(addr.clone());
+            status.connected = false;
+            status.preferred = found.is_none() && failover.on_preferred();
+            status.discovered = found.is_some();
+        }
+
+        let stream = match TcpStream::connect(&addr).await {
             Ok(s) => {
                 println!("[SERVER] Connected to root server");
                 s
             }
             Err(e) => {
                 println!("[SERVER] Connection failed: {}", e);
-                sleep(Duration::from_secs(5)).await;
+                record_failure(&mut failover, &root).await;
+                if wait_offline(
+                    &mut server_rx,
+                    &mut outbox,
+                    &mut shutdown,
+                    Duration::from_secs(5),
+                )
+                .await
+                {
+                    return Ok(());
+                }
                 continue;
             }
         };
 
-        let hello = format!("HELLO {} {}\n", client_id, version);
-
-        if stream.write_all(hello.as_bytes()).await.is_err() {
-            println!("[SERVER] Failed sending HELLO");
-            sleep(Duration::from_secs(3)).await;
-            continue;
-        }
+        let stream: BoxedStream = match &tls_config {
+            Some(t) => match secure(
+                stream,
+                t,
+                &ip,
+                found
+                    .as_ref()
+                    .filter(|f| f.tls)
+                    .and_then(|f| f.fingerprint.as_deref()),
+            )
+            .await
+            {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("[SERVER] TLS handshake failed: {}", e);
+                    record_failure(&mut failover, &root).await;
+                    if wait_offline(
+                        &mut server_rx,
+                        &mut outbox,
+                        &mut shutdown,
+                        Duration::from_secs(5),
+                    )
+                    .await
+                    {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            },
+            None => Box::new(stream),
+        };
 
-        let mut buffer = [0u8; 1024];
+        let mut conn = framed(stream);
 
-        let n = match stream.read(&mut buffer).await {
-            Ok(n) => n,
+        let protocol = match handshake(&mut conn, client_id, version, psk.as_deref()).await {
+            Ok(protocol) => protocol,
             Err(e) => {
-                println!("[SERVER] Handshake read failed: {}", e);
-                sleep(Duration::from_secs(3)).await;
+                println!("[SERVER] Handshake failed: {}", e);
+                record_failure(&mut failover, &root).await;
+                if wait_offline(
+                    &mut server_rx,
+                    &mut outbox,
+                    &mut shutdown,
+                    Duration::from_secs(3),
+                )
+                .await
+                {
+                    return Ok(());
+                }
                 continue;
             }
         };
 
-        let response = String::from_utf8_lossy(&buffer[..n]);
+        println!("[SERVER] Handshake success (protocol {})", protocol);
 
-        if response.trim() != "AKN" {
-            println!("[SERVER] Handshake rejected");
-            sleep(Duration::from_secs(3)).await;
-            continue;
+        failover.connected();
+        {
+            let mut status = root.lock().await;
+            status.connected = true;
+            status.failures = 0;
         }
 
-        println!("[SERVER] Handshake success");
+        // trust on first use: from now on only this server is accepted.
+        // Only a certificate checked during the TLS handshake is pinned;
+        // anything else in an announcement could have been made up.
+        let checked = found
+            .as_ref()
+            .filter(|f| f.tls && tls_config.is_some())
+            .and_then(|f| f.fingerprint.as_deref());
 
-        {
-            let u = user.lock().await;
+        if let Some(fingerprint) = checked {
+            if load_pin(&pin_path).is_none() {
+                println!("[DISCOVERY] Pinned root server {}", fingerprint);
+                save_pin(&pin_path, fingerprint);
+            }
+        }
 
-            let info = format!(
-                "INFO user {}\n{{\"name\":\"{}\",\"regno\":\"{}\"}}\n",
-                version, u.name, u.reg
-            );
+        if outbox.len() > 0 {
+            println!("[SERVER] Replaying {} queued messages", outbox.len());
 
-            let _ = stream.write_all(info.as_bytes()).await;
+            for message in outbox.replay() {
+                if conn.send(message).await.is_err() {
+                    break;
+                }
+            }
         }
 
         {
             let u = user.lock().await;
 
-            let info = format!(
-                "INFO user {}\n{{\"name\":\"{}\",\"regno\":\"{}\"}}\n",
-                version, u.name, u.reg
-            );
+            let info = Message::Info(Info {
+                name: u.name.clone(),
+                regno: u.reg.clone(),
+            });
 
             println!("Sending user state: {} {}", u.name, u.reg);
-            let _ = stream.write_all(info.as_bytes()).await;
+            let _ = conn.send(info).await;
         }
 
+        let mut ticker = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+        let mut last_seen = Instant::now();
+        let mut seq: u64 = 0;
+
+        let mut fallback = interval_at(Instant::now() + fallback_interval, fallback_interval);
+        let mut switching = false;
+
+        let (probe_tx, mut probe_rx) = mpsc::channel(1);
+        let mut probing = false;
+
+        let mut status = status_ticker(policy.subscribe().borrow().telemetry.status_interval);
+
         loop {
             tokio::select! {
 
                 _ = shutdown.recv() => {
                     println!("[SERVER] Shutdown signal received");
+
+                    // whatever was sent just before the shutdown, a stop
+                    // report in particular, still goes out or is queued
+                    while let Ok(msg) = server_rx.try_recv() {
+                        if conn.send(msg.clone()).await.is_err() {
+                            outbox.push(msg);
+                        }
+                    }
+
                     return Ok(());
                 }
 
-                result = stream.read(&mut buffer) => {
+                frame = conn.next() => {
 
-                    let n = match result {
-                        Ok(n) => n,
-                        Err(e) => {
+                    let message = match frame {
+                        Some(Ok(message)) => message,
+                        Some(Err(e)) => {
                             println!("[SERVER] Read error: {}", e);
                             break;
                         }
+                        None => {
+                            println!("[SERVER] Server disconnected");
+                            break;
+                        }
                     };
 
-                    if n == 0 {
-                        println!("[SERVER] Server disconnected");
+                    last_seen = Instant::now();
+
+                    if !matches!(message, Message::Ping { .. } | Message::Pong { .. } | Message::ReplayAck { .. }) {
+                        println!("[SERVER] {:?}", message);
+                    }
+
+                    match message {
+
+                        Message::Action(action) => match action.target {
+
+                            Target::Agent => {
+                                println!("[SELF ACTION] {}", action);
+
+                                let result = ActionResult::new(&action, false, "no self actions are supported");
+                                let _ = conn.send(Message::ActionResult(result)).await;
+                            }
+
+                            Target::Network => {
+
+                                if let Err(e) = network_tx.send(action).await {
+                                    println!("network channel closed {}", e);
+                                }
+                            }
+                        },
+
+                        Message::Ping { seq } => {
+                            let _ = conn.send(Message::Pong { seq }).await;
+                        }
+
+                        Message::ReplayAck { seq } => outbox.ack(seq),
+
+                        Message::Policy(new) => {
+                            let revision = new.revision;
+                            let every = new.telemetry.status_interval;
+
+                            let error = match policy.apply(new) {
+                                Ok(()) => {
+                                    status = status_ticker(every);
+                                    None
+                                }
+                                Err(e) => {
+                                    println!("[POLICY] Refused revision {}: {}", revision, e);
+                                    Some(e.to_string())
+                                }
+                            };
+
+                            let _ = conn.send(Message::PolicyAck { revision, error }).await;
+                        }
+
+                        _ => {}
+                    }
+                }
+
+                _ = ticker.tick() => {
+
+                    if last_seen.elapsed() > heartbeat_timeout {
+                        println!("[SERVER] Root server timed out");
                         break;
                     }
 
-                    let message = String::from_utf8_lossy(&buffer[..n]).to_string();
+                    seq += 1;
 
-                    println!("[SERVER] {}", message.trim());
+                    if let Err(e) = conn.send(Message::Ping { seq }).await {
+                        println!("Write failed {}", e);
+                        break;
+                    }
+                }
 
-                    if message.contains("ACTION self") {
-                        println!("[SELF ACTION] {}", message.trim());
+                _ = next_tick(&mut status) => {
+                    let report = status_report(&user, &outbox, &policy).await;
+
+                    if let Err(e) = conn.send(report).await {
+                        println!("Write failed {}", e);
+                        break;
                     }
+                }
 
-                    else if message.contains("ACTION network") {
+                Ok(()) = reloads.changed() => {
 
-                        if let Err(e) = network_tx.send(message.clone()).await {
-                            println!("network channel closed {}", e);
-                        }
+                    if config.lock().await.servers != failover.endpoints() {
+                        println!("[SERVER] Server list changed, reconnecting");
+                        switching = true;
+                        break;
+                    }
+                }
+
+                // probed in its own task so a preferred server that drops
+                // packets does not hold up heartbeats for the timeout
+                _ = fallback.tick(), if !failover.on_preferred() && !probing => {
+
+                    let preferred = failover.preferred().map(|p| p.to_string()).unwrap_or_default();
+                    let probe_tx = probe_tx.clone();
+                    probing = true;
+
+                    tokio::spawn(async move {
+                        let up = reachable(&preferred).await;
+                        let _ = probe_tx.send((preferred, up)).await;
+                    });
+                }
+
+                Some((preferred, up)) = probe_rx.recv() => {
+                    probing = false;
+
+                    if up && !failover.on_preferred() {
+                        println!("[SERVER] Preferred root server {} is back, switching", preferred);
+                        failover.fall_back();
+                        switching = true;
+                        break;
                     }
                 }
 
                 Some(msg) = server_rx.recv() => {
 
-                    if let Err(e) = stream.write_all(msg.as_bytes()).await {
+                    if let Err(e) = conn.send(msg.clone()).await {
                         println!("Write failed {}", e);
+                        outbox.push(msg);
                         break;
                     }
                 }
             }
         }
 
+        root.lock().await.connected = false;
+
+        if switching {
+            continue;
+        }
+
         println!("[SERVER] Reconnecting in 5 seconds...");
-        sleep(Duration::from_secs(5)).await;
+        if wait_offline(
+            &mut server_rx,
+            &mut outbox,
+            &mut shutdown,
+            Duration::from_secs(5),
+        )
+        .await
+        {
+            return Ok(());
+        }
     }
 }
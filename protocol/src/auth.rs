@@ -0,0 +1,64 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Fresh random challenge sent by sentineld, hex encoded.
+pub fn nonce() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+fn mac(secret: &str, parts: &[&str]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+
+    // fields are separated so "1" + "23" never collides with "12" + "3"
+    for part in parts {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+
+    mac
+}
+
+fn check(mac: HmacSha256, answer: &str) -> bool {
+    match hex::decode(answer) {
+        Ok(bytes) => mac.verify_slice(&bytes).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Answer to a challenge: HMAC-SHA256 over nonce, client id and version.
+pub fn sign(secret: &str, nonce: &str, client_id: &str, version: &str) -> String {
+    hex::encode(
+        mac(secret, &[nonce, client_id, version])
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+pub fn verify(secret: &str, nonce: &str, client_id: &str, version: &str, answer: &str) -> bool {
+    check(mac(secret, &[nonce, client_id, version]), answer)
+}
+
+// Proof in a discovery announcement that the server holds the client's
+// key: HMAC-SHA256 over the probe's nonce, the client id and the address
+// announced. The leading label keeps it apart from handshake answers, so
+// neither can stand in for the other.
+pub fn sign_announce(secret: &str, nonce: &str, client_id: &str, addr: &str) -> String {
+    hex::encode(
+        mac(secret, &["announce", nonce, client_id, addr])
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+pub fn verify_announce(
+    secret: &str,
+    nonce: &str,
+    client_id: &str,
+    addr: &str,
+    proof: &str,
+) -> bool {
+    check(mac(secret, &["announce", nonce, client_id, addr]), proof)
+}
@@ -0,0 +1,68 @@
+use bytes::{Bytes, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec};
+
+use crate::message::Message;
+
+// Upper bound for a single frame, anything bigger is treated as a broken peer.
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+// Frames are a 4 byte big-endian length followed by a JSON encoded Message.
+pub struct MessageCodec {
+    inner: LengthDelimitedCodec,
+}
+
+pub type Connection<T> = Framed<T, MessageCodec>;
+
+// Lets plain TCP and TLS links share one connection type.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+pub fn framed<T: AsyncRead + AsyncWrite>(io: T) -> Connection<T> {
+    Framed::new(io, MessageCodec::new())
+}
+
+impl MessageCodec {
+    pub fn new() -> MessageCodec {
+        MessageCodec {
+            inner: LengthDelimitedCodec::builder()
+                .max_frame_length(MAX_FRAME_LENGTH)
+                .new_codec(),
+        }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, io::Error> {
+        match self.inner.decode(src)? {
+            Some(frame) => serde_json::from_slice(&frame)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let payload =
+            serde_json::to_vec(&item).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        self.inner.encode(Bytes::from(payload), dst)
+    }
+}
@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::Ipv4Addr;
+
+// Sentries look for sentineld by sending a Probe to this port, both as a
+// LAN broadcast and to the multicast group, and wait for an Announce.
+pub const DISCOVERY_PORT: u16 = 1613;
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 16, 12);
+
+// Datagrams are a single JSON object, small enough to never fragment.
+pub const MAX_DATAGRAM: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Discovery {
+    Probe {
+        // echoed back so stray announcements can be told apart
+        nonce: String,
+        // sent by a sentry with a pre-shared key, so the server knows which
+        // key to prove it holds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
+    },
+    Announce {
+        nonce: String,
+        ip: String,
+        port: u16,
+        // sha256 of the server certificate; only a TLS server has one, and
+        // only it can be pinned
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
+        tls: bool,
+        // auth::sign_announce with the client's key, when it sent its id
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<String>,
+    },
+}
+
+impl Discovery {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Discovery> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+// Lowercase hex sha256, the form fingerprints are announced and pinned in.
+pub fn fingerprint(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
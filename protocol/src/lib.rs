@@ -0,0 +1,12 @@
+pub mod auth;
+pub mod codec;
+pub mod discovery;
+pub mod local;
+pub mod message;
+pub mod policy;
+pub mod reload;
+pub mod tls;
+
+pub use codec::{framed, BoxedStream, Connection, MessageCodec, Stream};
+pub use message::*;
+pub use policy::Policy;
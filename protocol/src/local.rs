@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Context, Result};
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+
+// Who is on the other end of a local control socket, from SO_PEERCRED.
+// None if the kernel would not say.
+#[derive(Clone, Debug)]
+pub struct PeerCred {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub pid: Option<i32>,
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for PeerCred {
+    fn connect_info(stream: IncomingStream<'_, UnixListener>) -> Self {
+        let cred = stream.io().peer_cred().ok();
+
+        PeerCred {
+            uid: cred.map(|c| c.uid()),
+            gid: cred.map(|c| c.gid()),
+            pid: cred.and_then(|c| c.pid()),
+        }
+    }
+}
+
+impl PeerCred {
+    // Shown as the caller's address in logs.
+    pub fn describe(&self) -> String {
+        match (self.uid, self.pid) {
+            (Some(uid), Some(pid)) => format!("unix:uid={},pid={}", uid, pid),
+            (Some(uid), None) => format!("unix:uid={}", uid),
+            _ => "unix".to_string(),
+        }
+    }
+}
+
+// Makes sure `dir` can only be changed by root, or by the daemon's own
+// user when it is not running as root: owned by one of them and not
+// writable by group or others. A missing directory is created with `mode`.
+// Anything else is refused, since whoever can write to it can replace
+// what the daemon keeps there.
+pub fn secure_dir(dir: &Path, mode: u32) -> Result<()> {
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(mode)
+            .create(dir)
+            .with_context(|| format!("creating {}", dir.display()))?;
+
+        // the umask may have taken bits away, never added them
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("setting permissions of {}", dir.display()))?;
+    }
+
+    let meta = fs::metadata(dir).with_context(|| format!("reading {}", dir.display()))?;
+
+    if !meta.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+
+    let euid = nix::unistd::geteuid().as_raw();
+
+    if meta.uid() != 0 && meta.uid() != euid {
+        return Err(anyhow!(
+            "{} is owned by uid {}, not root",
+            dir.display(),
+            meta.uid()
+        ));
+    }
+
+    if meta.mode() & 0o022 != 0 {
+        return Err(anyhow!(
+            "{} is writable by group or others ({:#o})",
+            dir.display(),
+            meta.mode() & 0o777
+        ));
+    }
+
+    Ok(())
+}
+
+// Binds `path` with the given permission bits, replacing a socket left
+// behind by an earlier run but never any other kind of file. The directory
+// must pass secure_dir; it is created 0700, or 0755 when the socket itself
+// is meant for other users.
+pub fn bind(path: &str, mode: u32) -> Result<UnixListener> {
+    if let Some(dir) = Path::new(path).parent() {
+        let dir_mode = if mode & 0o077 == 0 { 0o700 } else { 0o755 };
+        secure_dir(dir, dir_mode)?;
+    }
+
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path));
+        }
+
+        fs::remove_file(path).with_context(|| format!("removing stale {}", path))?;
+    }
+
+    let listener = UnixListener::bind(path).with_context(|| format!("binding {}", path))?;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("setting permissions of {}", path))?;
+
+    Ok(listener)
+}
+
+// Replaces `path` with `data` in one step: written to `<path>.tmp`, created
+// afresh with `mode` and without following a link planted there, then
+// renamed over `path`, so a crash never leaves a half written file.
+pub fn write_atomic(path: &str, data: &[u8], mode: u32) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let _ = fs::remove_file(&tmp);
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("writing {}", path))
+}
+
+// Opens `path` for appending, creating it with `mode` if need be, but never
+// through a link planted in its place.
+pub fn append(path: &str, mode: u32) -> std::io::Result<fs::File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(mode)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(path)
+}
+
+// Reads a file the daemon keeps for itself, None if there is none. It must
+// be owned by the daemon's user and writable by nobody else, or it could
+// hold anything; a link is not followed.
+pub fn read_trusted(path: &str) -> Result<Option<String>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(path);
+
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("opening {}", path)),
+    };
+
+    let meta = file
+        .metadata()
+        .with_context(|| format!("reading {}", path))?;
+    let euid = nix::unistd::geteuid().as_raw();
+
+    if meta.uid() != euid {
+        return Err(anyhow!(
+            "{} is owned by uid {}, not {}",
+            path,
+            meta.uid(),
+            euid
+        ));
+    }
+
+    if meta.mode() & 0o022 != 0 {
+        return Err(anyhow!(
+            "{} is writable by group or others ({:#o})",
+            path,
+            meta.mode() & 0o777
+        ));
+    }
+
+    let mut text = String::new();
+    file.read_to_string(&mut text)
+        .with_context(|| format!("reading {}", path))?;
+
+    Ok(Some(text))
+}
@@ -0,0 +1,293 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::policy::Policy;
+
+// Protocol versions this build can speak, newest last.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+// Every frame on the sentry <-> sentineld link carries exactly one Message,
+// serialized as JSON and tagged by "type".
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Hello {
+        client_id: String,
+        version: String,
+        protocols: Vec<u32>,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    Challenge {
+        nonce: String,
+    },
+    Auth {
+        mac: String,
+    },
+    HelloAck {
+        client_id: String,
+        protocol: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+
+    Info(Info),
+
+    Action(Action),
+    ActionResult(ActionResult),
+
+    Telemetry(Telemetry),
+
+    // The whole policy for this client, replacing whatever it had. Only
+    // sent to sentries that negotiated the `policy` capability.
+    Policy(Policy),
+    // `error` is set when the policy was refused and the old one kept.
+    PolicyAck {
+        revision: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+
+    // A message the sentry queued while offline, sent after reconnecting.
+    // `seq` only grows, so sentineld can drop anything it already saw.
+    Replayed {
+        seq: u64,
+        queued_at: u64,
+        message: Box<Message>,
+    },
+    ReplayAck {
+        seq: u64,
+    },
+
+    Ping {
+        seq: u64,
+    },
+    Pong {
+        seq: u64,
+    },
+
+    Error {
+        message: String,
+    },
+}
+
+// Features a sentry can offer. Names we do not know yet (from a newer
+// sentry) decode as Unknown and are dropped during negotiation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    BrowserMonitor,
+    Firewall,
+    FileTransfer,
+    ProcessMonitor,
+    Policy,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    pub name: String,
+    pub regno: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    // actions handled by the sentry daemon itself
+    #[serde(rename = "self")]
+    Agent,
+    Network,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+    // correlation id, echoed back in the ActionResult
+    #[serde(default)]
+    pub id: u64,
+    pub target: Target,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ActionResult {
+    #[serde(default)]
+    pub id: u64,
+    pub target: Target,
+    pub command: String,
+    pub ok: bool,
+    #[serde(default)]
+    pub output: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Telemetry {
+    pub source: String,
+    pub timestamp: u64,
+    pub data: serde_json::Value,
+}
+
+// Telemetry source whose data is a StopAttempt.
+pub const STOP_ATTEMPT: &str = "stop_attempt";
+
+// Someone asked the sentry to stop, whether or not it did. Sent as
+// telemetry so an older sentineld simply logs it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StopAttempt {
+    // "socket", "http" or "signal"
+    pub via: String,
+    pub allowed: bool,
+    // local caller, when the kernel told us
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub pid: Option<i32>,
+}
+
+impl StopAttempt {
+    pub fn telemetry(&self, timestamp: u64) -> Message {
+        Message::Telemetry(Telemetry {
+            source: STOP_ATTEMPT.to_string(),
+            timestamp,
+            data: serde_json::to_value(self).unwrap_or_default(),
+        })
+    }
+}
+
+// Highest version both sides support.
+pub fn negotiate_version(offered: &[u32]) -> Option<u32> {
+    offered
+        .iter()
+        .filter(|v| PROTOCOL_VERSIONS.contains(v))
+        .max()
+        .copied()
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::BrowserMonitor => "browser_monitor",
+            Capability::Firewall => "firewall",
+            Capability::FileTransfer => "file_transfer",
+            Capability::ProcessMonitor => "process_monitor",
+            Capability::Policy => "policy",
+            Capability::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Target {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Target::Agent => "self",
+            Target::Network => "network",
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Target {
+    type Err = ParseActionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "self" => Ok(Target::Agent),
+            "network" => Ok(Target::Network),
+            other => Err(ParseActionError::UnknownTarget(other.to_string())),
+        }
+    }
+}
+
+impl Action {
+    // Capability the sentry must have negotiated to carry out this action.
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self.target {
+            Target::Agent => None,
+            Target::Network => Some(Capability::Firewall),
+        }
+    }
+}
+
+impl ActionResult {
+    pub fn new(action: &Action, ok: bool, output: impl Into<String>) -> ActionResult {
+        ActionResult {
+            id: action.id,
+            target: action.target,
+            command: action.command.clone(),
+            ok,
+            output: output.into(),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ACTION {} {}", self.target, self.command)?;
+
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Parses the text form used by the CLI: `[ACTION] <target> <command> [args..]`
+impl FromStr for Action {
+    type Err = ParseActionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace().peekable();
+
+        if parts.peek() == Some(&"ACTION") {
+            parts.next();
+        }
+
+        let target = parts.next().ok_or(ParseActionError::Empty)?.parse()?;
+        let command = parts
+            .next()
+            .ok_or(ParseActionError::MissingCommand)?
+            .to_string();
+        let args = parts.map(str::to_string).collect();
+
+        Ok(Action {
+            id: 0,
+            target,
+            command,
+            args,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseActionError {
+    Empty,
+    UnknownTarget(String),
+    MissingCommand,
+}
+
+impl fmt::Display for ParseActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseActionError::Empty => write!(f, "empty action"),
+            ParseActionError::UnknownTarget(t) => write!(f, "unknown action target '{}'", t),
+            ParseActionError::MissingCommand => write!(f, "action is missing a command"),
+        }
+    }
+}
+
+impl std::error::Error for ParseActionError {}
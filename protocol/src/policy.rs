@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// What sentineld wants a sentry to do, pushed as one document. Every field
+// has a default, and the all-default document (revision 0) is what a
+// sentry runs with before it has been sent anything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Policy {
+    // set by sentineld, echoed back in PolicyAck
+    pub revision: u64,
+    pub monitors: Monitors,
+    // browser visits go here instead of the sentry's own kafka.topic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kafka_topic: Option<String>,
+    // first matching rule wins
+    pub url_rules: Vec<UrlRule>,
+    pub firewall_rules: Vec<FirewallRule>,
+    pub telemetry: TelemetryPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Monitors {
+    pub browser: bool,
+    pub network: bool,
+}
+
+// Matches a URL whose host is `host` or one of its subdomains.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UrlRule {
+    pub host: String,
+    pub action: UrlAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UrlAction {
+    Allow,
+    // reported with the visit
+    Flag,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FirewallRule {
+    pub ip: String,
+    pub action: FirewallAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FirewallAction {
+    Allow,
+    Block,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TelemetryPolicy {
+    // seconds between status reports to the root, 0 for none
+    pub status_interval: u64,
+}
+
+impl Default for Monitors {
+    fn default() -> Self {
+        Monitors {
+            browser: true,
+            network: true,
+        }
+    }
+}
+
+// "https://docs.example.com:8443/x" -> "docs.example.com"
+fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    host.split(':').next().unwrap_or("")
+}
+
+impl UrlRule {
+    pub fn matches(&self, url: &str) -> bool {
+        let host = url_host(url).to_ascii_lowercase();
+        let rule = self.host.to_ascii_lowercase();
+
+        host == rule || host.ends_with(&format!(".{}", rule))
+    }
+}
+
+impl Policy {
+    // The rule deciding what happens to a visit to `url`, if any.
+    pub fn url_rule(&self, url: &str) -> Option<&UrlRule> {
+        self.url_rules.iter().find(|rule| rule.matches(url))
+    }
+
+    // Checked by sentineld before storing and again by the sentry before
+    // applying. Errors name the field the way it is written in JSON.
+    pub fn validate(&self) -> Result<()> {
+        if self.kafka_topic.as_deref() == Some("") {
+            return Err(anyhow!("kafka_topic: must not be empty"));
+        }
+
+        for (i, rule) in self.url_rules.iter().enumerate() {
+            if rule.host.is_empty() || rule.host.contains(['/', ':']) {
+                return Err(anyhow!(
+                    "url_rules[{}].host: '{}' is not a host name",
+                    i,
+                    rule.host
+                ));
+            }
+        }
+
+        for (i, rule) in self.firewall_rules.iter().enumerate() {
+            if rule.ip.parse::<Ipv4Addr>().is_err() {
+                return Err(anyhow!(
+                    "firewall_rules[{}].ip: '{}' is not an IPv4 address",
+                    i,
+                    rule.ip
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
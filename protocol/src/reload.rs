@@ -0,0 +1,125 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use tokio::signal::unix::{signal, SignalKind};
+
+// Outcome of a reload, by dotted key name. Values are left out since some
+// of them are secrets.
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+// `live` holds the keys picked up without a restart, matched as prefixes
+// of dotted key names.
+fn is_live(key: &str, live: &[&str]) -> bool {
+    live.iter()
+        .any(|live| key == *live || key.starts_with(&format!("{}.", live)))
+}
+
+fn flatten(prefix: String, value: Value, out: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(key, value, out);
+            }
+        }
+        other => {
+            out.insert(prefix, other);
+        }
+    }
+}
+
+// Dotted names of every key whose value differs between the two configs.
+fn changed_keys<T: Serialize>(old: &T, new: &T) -> Result<Vec<String>> {
+    let (mut before, mut after) = (BTreeMap::new(), BTreeMap::new());
+    flatten(String::new(), serde_json::to_value(old)?, &mut before);
+    flatten(String::new(), serde_json::to_value(new)?, &mut after);
+
+    let keys: BTreeSet<&String> = before
+        .keys()
+        .chain(after.keys())
+        .filter(|key| before.get(*key) != after.get(*key))
+        .collect();
+
+    Ok(keys.into_iter().cloned().collect())
+}
+
+impl ReloadReport {
+    // Sorts every changed key into applied or restart_required.
+    pub fn compare<T: Serialize>(old: &T, new: &T, live: &[&str]) -> Result<ReloadReport> {
+        let mut report = ReloadReport::default();
+
+        for key in changed_keys(old, new)? {
+            if is_live(&key, live) {
+                report.applied.push(key);
+            } else {
+                report.restart_required.push(key);
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+// `tag` is the daemon's log prefix, e.g. "[CONFIG]".
+pub fn log_report(tag: &str, report: &ReloadReport) {
+    println!(
+        "{} Reloaded, applied {:?}, restart required for {:?}",
+        tag, report.applied, report.restart_required
+    );
+}
+
+// Calls `reload` on every SIGHUP and logs how it went.
+pub async fn watch_sighup<F, Fut>(tag: &str, reload: F) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<ReloadReport>>,
+{
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        match reload().await {
+            Ok(report) => log_report(tag, &report),
+            Err(e) => println!("{} Reload rejected: {}", tag, e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sorts_changed_keys_by_live_prefix() {
+        let old = json!({"log": {"level": "info", "dir": "/a"}, "kafka": {"brokers": "x"}});
+        let new = json!({"log": {"level": "debug", "dir": "/b"}, "kafka": {"brokers": "y"}});
+
+        let report = ReloadReport::compare(&old, &new, &["log.level", "kafka"]).unwrap();
+
+        assert_eq!(report.applied, vec!["kafka.brokers", "log.level"]);
+        assert_eq!(report.restart_required, vec!["log.dir"]);
+    }
+
+    #[test]
+    fn prefix_must_end_at_a_dot() {
+        let old = json!({"log": {"level": "info", "levels": 1}});
+        let new = json!({"log": {"level": "info", "levels": 2}});
+
+        let report = ReloadReport::compare(&old, &new, &["log.level"]).unwrap();
+
+        assert!(report.applied.is_empty());
+        assert_eq!(report.restart_required, vec!["log.levels"]);
+    }
+}
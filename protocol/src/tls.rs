@@ -0,0 +1,82 @@
+use anyhow::{anyhow, Context, Result};
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::discovery::fingerprint;
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("reading certificates from {}", path))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("parsing certificates in {}", path))?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates found in {}", path));
+    }
+
+    Ok(certs)
+}
+
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).with_context(|| format!("reading private key {}", path))
+}
+
+fn load_roots(ca: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certs(ca)? {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
+
+// Server side: only clients presenting a certificate signed by `ca` are accepted.
+pub fn acceptor(ca: &str, cert: &str, key: &str) -> Result<TlsAcceptor> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca)?)).build()?;
+
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certs(cert)?, load_key(key)?)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Client side: the server must be signed by `ca`, and we present our own cert.
+pub fn connector(ca: &str, cert: &str, key: &str) -> Result<TlsConnector> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(load_roots(ca)?)
+        .with_client_auth_cert(load_certs(cert)?, load_key(key)?)?;
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub fn server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_string())
+        .map_err(|e| anyhow!("invalid server name {}: {}", name, e))
+}
+
+// Common name of the verified peer certificate, used as the client identity.
+pub fn peer_identity(certs: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    let leaf = certs?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf.as_ref()).ok()?;
+
+    let cn = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+    Some(cn.to_string())
+}
+
+// Fingerprint of the first certificate in `path`, as announced by discovery.
+pub fn cert_fingerprint(path: &str) -> Result<String> {
+    let certs = load_certs(path)?;
+    Ok(fingerprint(certs[0].as_ref()))
+}
+
+// Fingerprint of the certificate the peer presented, if any.
+pub fn peer_fingerprint(certs: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    Some(fingerprint(certs?.first()?.as_ref()))
+}
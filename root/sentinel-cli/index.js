@@ -1,6 +1,12 @@
 #!/usr/bin/env node
 
 const BASE_URL = "http://127.0.0.1:3737";
+// created with `sentineld token add NAME --role ROLE`
+const TOKEN = process.env.SENTINEL_TOKEN;
+
+function headers(extra = {}) {
+  return TOKEN ? { ...extra, Authorization: `Bearer ${TOKEN}` } : extra;
+}
 
 function formatTable(data) {
   if (!data.length) {
@@ -22,9 +28,19 @@ function formatTable(data) {
   }
 }
 
+function printJob(job) {
+  console.log(`Job ${job.id}: ${job.status}`);
+  if (job.output) {
+    console.log(job.output);
+  }
+  if (job.reason) {
+    console.log(job.reason);
+  }
+}
+
 async function get(path) {
   try {
-    const res = await fetch(`${BASE_URL}${path}`);
+    const res = await fetch(`${BASE_URL}${path}`, { headers: headers() });
     const data = await res.json();
     return data;
   } catch (err) {
@@ -38,9 +54,9 @@ async function post(path, body = {}) {
   try {
     const res = await fetch(`${BASE_URL}${path}`, {
       method: "POST",
-      headers: {
+      headers: headers({
         "Content-Type": "application/json",
-      },
+      }),
       body: JSON.stringify(body),
     });
 
@@ -65,6 +81,9 @@ Usage:
   sentinel ls
   sentinel stop
   sentinel send <id> <message>
+  sentinel job <job-id>
+
+Set SENTINEL_TOKEN to an API token from \`sentineld token add\`.
 `);
     process.exit(0);
   }
@@ -108,6 +127,25 @@ Usage:
       const res = await post("/send", { id, message });
 
       console.log(res.message || res);
+      if (res.job) {
+        printJob(res.job);
+      }
+      break;
+    }
+
+    case "job": {
+      if (args.length < 2) {
+        console.log("Usage: sentinel job <job-id>");
+        process.exit(1);
+      }
+
+      const res = await get(`/jobs/${parseInt(args[1])}`);
+
+      if (res.id) {
+        printJob(res);
+      } else {
+        console.log(res.message || res);
+      }
       break;
     }
 
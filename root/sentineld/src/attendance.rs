@@ -0,0 +1,183 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::registry::LoginRecord;
+
+// Time one student spent logged in during the window.
+#[derive(Debug, Serialize)]
+pub struct Attendance {
+    pub regno: String,
+    // as given on their latest login
+    pub name: String,
+    pub machines: Vec<usize>,
+    pub logins: usize,
+    // unix seconds, cut to the window
+    pub first_seen: u64,
+    pub last_seen: u64,
+    // seconds; time logged in on two machines at once counts once
+    pub present: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub since: u64,
+    pub until: u64,
+    pub students: Vec<Attendance>,
+}
+
+// Builds the report from logins overlapping [since, until], oldest first.
+// Logins still open count up to `until`.
+pub fn report(logins: &[LoginRecord], since: u64, until: u64) -> Report {
+    let mut by_regno: BTreeMap<&str, Vec<&LoginRecord>> = BTreeMap::new();
+
+    for login in logins {
+        by_regno.entry(&login.regno).or_default().push(login);
+    }
+
+    let students = by_regno
+        .into_iter()
+        .map(|(regno, logins)| {
+            let spans: Vec<(u64, u64)> = logins
+                .iter()
+                .map(|l| (l.started.max(since), l.ended.unwrap_or(until).min(until)))
+                .filter(|(start, end)| start <= end)
+                .collect();
+
+            let mut machines: Vec<usize> = logins.iter().map(|l| l.client).collect();
+            machines.sort_unstable();
+            machines.dedup();
+
+            Attendance {
+                regno: regno.to_string(),
+                name: logins.last().map(|l| l.name.clone()).unwrap_or_default(),
+                machines,
+                logins: logins.len(),
+                first_seen: spans.iter().map(|(start, _)| *start).min().unwrap_or(since),
+                last_seen: spans.iter().map(|(_, end)| *end).max().unwrap_or(since),
+                present: covered(spans),
+            }
+        })
+        .collect();
+
+    Report {
+        since,
+        until,
+        students,
+    }
+}
+
+// Total length of the union of the spans.
+fn covered(mut spans: Vec<(u64, u64)>) -> u64 {
+    spans.sort_unstable();
+
+    let mut total = 0;
+    let mut current: Option<(u64, u64)> = None;
+
+    for (start, end) in spans {
+        current = match current {
+            Some((s, e)) if start <= e => Some((s, e.max(end))),
+            Some((s, e)) => {
+                total += e - s;
+                Some((start, end))
+            }
+            None => Some((start, end)),
+        };
+    }
+
+    total + current.map_or(0, |(s, e)| e - s)
+}
+
+// One line per student, machines separated by ';'.
+pub fn csv(report: &Report) -> String {
+    let mut out = String::from("regno,name,machines,logins,first_seen,last_seen,present\n");
+
+    for s in &report.students {
+        let machines: Vec<String> = s.machines.iter().map(|id| id.to_string()).collect();
+
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            field(&s.regno),
+            field(&s.name),
+            machines.join(";"),
+            s.logins,
+            s.first_seen,
+            s.last_seen,
+            s.present
+        ));
+    }
+
+    out
+}
+
+// Quotes a value typed in by a student if it would break the row. One a
+// spreadsheet would take for a formula gets a leading ' so it stays text.
+fn field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(client: usize, regno: &str, started: u64, ended: Option<u64>) -> LoginRecord {
+        LoginRecord {
+            client,
+            name: "Asha".into(),
+            regno: regno.into(),
+            started,
+            ended,
+            reason: None,
+            duration: 0,
+        }
+    }
+
+    #[test]
+    fn covered_counts_overlaps_once() {
+        assert_eq!(covered(vec![]), 0);
+        assert_eq!(covered(vec![(10, 20), (30, 40)]), 20);
+        assert_eq!(covered(vec![(30, 40), (10, 35)]), 30);
+        assert_eq!(covered(vec![(10, 40), (15, 20)]), 30);
+        assert_eq!(covered(vec![(10, 20), (20, 30)]), 20);
+    }
+
+    #[test]
+    fn fields_cannot_break_rows_or_become_formulas() {
+        assert_eq!(field("Asha"), "Asha");
+        assert_eq!(field("Rao, Asha"), "\"Rao, Asha\"");
+        assert_eq!(field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(field("+1"), "'+1");
+        assert_eq!(field("-2"), "'-2");
+        assert_eq!(field("@SUM(A1)"), "'@SUM(A1)");
+    }
+
+    #[test]
+    fn report_cuts_logins_to_the_window() {
+        let logins = vec![
+            login(1, "21CS001", 50, Some(150)),
+            login(2, "21CS001", 120, None),
+            login(3, "21CS002", 10, Some(90)),
+        ];
+
+        let report = report(&logins, 100, 200);
+        let first = &report.students[0];
+
+        assert_eq!(first.regno, "21CS001");
+        assert_eq!(first.machines, vec![1, 2]);
+        assert_eq!((first.first_seen, first.last_seen), (100, 200));
+        assert_eq!(first.present, 100);
+
+        let second = &report.students[1];
+        assert_eq!(second.present, 0);
+        assert_eq!((second.first_seen, second.last_seen), (100, 100));
+    }
+}
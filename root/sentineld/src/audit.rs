@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use sentinel_protocol::local;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::unix_time;
+
+// One admin action through the HTTP API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: u64,
+    // who made the call and from where
+    pub actor: String,
+    pub addr: String,
+    // e.g. "send", "stop", "reload", "policy.set"
+    pub action: String,
+    // clients the action was aimed at or reached
+    pub targets: Vec<usize>,
+    pub ok: bool,
+    pub detail: String,
+}
+
+// Who is calling the API: the name of their bearer token, or on the unix
+// socket the local user SO_PEERCRED reported. "anonymous" only when
+// authentication is turned off. `addr` is the TCP peer, or the uid and pid
+// on the socket.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub actor: String,
+    pub addr: String,
+}
+
+// Filters for reading the log back. Every set field must match.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+// Append-only JSON lines file. The daemon never rewrites or truncates it,
+// so it can additionally be protected with `chattr +a`. It lives in a
+// directory only root can change, and is never opened through a link.
+pub struct Audit {
+    path: String,
+    file: Mutex<File>,
+}
+
+pub type SharedAudit = Arc<Audit>;
+
+const MAX_RESULTS: usize = 1000;
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.at >= since)
+            && self.until.is_none_or(|until| entry.at <= until)
+            && self
+                .actor
+                .as_ref()
+                .is_none_or(|actor| entry.actor == *actor)
+            && self
+                .action
+                .as_ref()
+                .is_none_or(|action| entry.action == *action)
+            && self
+                .target
+                .is_none_or(|target| entry.targets.contains(&target))
+    }
+}
+
+impl Audit {
+    pub fn open(path: &str) -> Result<Audit> {
+        if let Some(dir) = Path::new(path).parent() {
+            local::secure_dir(dir, 0o700)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .custom_flags(nix::libc::O_NOFOLLOW)
+            .open(path)
+            .with_context(|| format!("opening {}", path))?;
+
+        Ok(Audit {
+            path: path.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        caller: &Caller,
+        action: &str,
+        targets: Vec<usize>,
+        ok: bool,
+        detail: impl Into<String>,
+    ) {
+        let entry = AuditEntry {
+            at: unix_time(),
+            actor: caller.actor.clone(),
+            addr: caller.addr.clone(),
+            action: action.to_string(),
+            targets,
+            ok,
+            detail: detail.into(),
+        };
+
+        let written = serde_json::to_string(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(self.file.lock().unwrap(), "{}", line));
+
+        if let Err(e) = written {
+            println!("[AUDIT]: Could not record {:?}: {}", entry, e);
+        }
+    }
+
+    // Matching entries, newest first. Blocks while reading the file, but
+    // only ever holds the last `limit` matches, however long it is.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NOFOLLOW)
+            .open(&self.path)
+            .with_context(|| format!("reading {}", self.path))?;
+        let limit = query.limit.unwrap_or(100).min(MAX_RESULTS);
+
+        let mut newest = VecDeque::with_capacity(limit + 1);
+
+        let matching = BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(&line).ok())
+            .filter(|entry| query.matches(entry));
+
+        for entry in matching {
+            newest.push_back(entry);
+
+            if newest.len() > limit {
+                newest.pop_front();
+            }
+        }
+
+        Ok(newest.into_iter().rev().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audit(name: &str) -> Audit {
+        let path =
+            std::env::temp_dir().join(format!("sentineld-audit-{}/{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        Audit::open(&path.to_string_lossy()).unwrap()
+    }
+
+    fn caller() -> Caller {
+        Caller {
+            actor: "admin".into(),
+            addr: "127.0.0.1".into(),
+        }
+    }
+
+    #[test]
+    fn returns_the_newest_matches_first() {
+        let audit = audit("newest");
+
+        for id in 0..10 {
+            audit.record(&caller(), "send", vec![id], true, "");
+            audit.record(&caller(), "kick", vec![id], true, "");
+        }
+
+        let query = AuditQuery {
+            action: Some("send".into()),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let targets: Vec<usize> = audit
+            .query(&query)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.targets[0])
+            .collect();
+
+        assert_eq!(targets, vec![9, 8, 7]);
+    }
+
+    #[test]
+    fn zero_limit_returns_nothing() {
+        let audit = audit("zero");
+        audit.record(&caller(), "send", vec![1], true, "");
+
+        let query = AuditQuery {
+            limit: Some(0),
+            ..Default::default()
+        };
+
+        assert!(audit.query(&query).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+use sentinel_protocol::local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::cli::TokenCommand;
+use crate::unix_time;
+
+// What a token may do. Each role can do everything the ones before it can.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // read only: clients, history, groups, policies, reports
+    Viewer,
+    // also sends commands, disconnects clients and reads client logs
+    Invigilator,
+    // also changes policies and groups, reloads, stops, reads the audit log
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Invigilator => "invigilator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+// The caller a request was authenticated as.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+// Only a hash of each token is kept, so the file does not let anyone in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TokenEntry {
+    pub name: String,
+    pub role: Role,
+    hash: String,
+    pub created: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenFile {
+    tokens: Vec<TokenEntry>,
+}
+
+// Tokens managed with `sentineld token`. The file is read again whenever
+// it changes, so tokens added or removed apply without a reload.
+pub struct TokenStore {
+    path: String,
+    cache: Mutex<(Option<SystemTime>, Vec<TokenEntry>)>,
+}
+
+pub type SharedTokens = Arc<TokenStore>;
+
+// Least role allowed on each route, by method and route pattern. Routes
+// not listed are admin only.
+const PERMISSIONS: &[(&str, &str, Role)] = &[
+    ("GET", "/status", Role::Viewer),
+    ("GET", "/clients", Role::Viewer),
+    ("GET", "/clients/offline", Role::Viewer),
+    ("GET", "/clients/{id}", Role::Viewer),
+    ("DELETE", "/clients/{id}", Role::Invigilator),
+    ("POST", "/clients/{id}/commands", Role::Invigilator),
+    ("GET", "/clients/{id}/history", Role::Viewer),
+    ("GET", "/clients/{id}/log", Role::Invigilator),
+    ("GET", "/logins", Role::Viewer),
+    ("GET", "/attendance", Role::Viewer),
+    ("POST", "/send", Role::Invigilator),
+    ("POST", "/broadcast", Role::Invigilator),
+    ("GET", "/jobs/{id}", Role::Viewer),
+    ("GET", "/groups", Role::Viewer),
+    ("GET", "/policies", Role::Viewer),
+    ("GET", "/kafka/ws", Role::Viewer),
+    ("GET", "/events", Role::Viewer),
+    ("GET", "/events/ws", Role::Viewer),
+];
+
+pub fn required_role(method: &str, route: &str) -> Role {
+    PERMISSIONS
+        .iter()
+        .find(|(m, r, _)| *m == method && *r == route)
+        .map_or(Role::Admin, |(_, _, role)| *role)
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// A tokens file anyone but the daemon's user could have written is refused
+// rather than trusted.
+fn read(path: &str) -> Result<TokenFile> {
+    let Some(text) = local::read_trusted(path)? else {
+        return Ok(TokenFile::default());
+    };
+
+    serde_json::from_str(&text).map_err(|e| anyhow!("{}: {}", path, e))
+}
+
+// Written owner-only; the hashes are not secret, but the list of names and
+// roles is nobody else's business. The directory is created 0700 if need
+// be, and must not be writable by anyone else.
+fn write(path: &str, file: &TokenFile) -> Result<()> {
+    let text = serde_json::to_string_pretty(file)?;
+
+    if let Some(dir) = Path::new(path).parent() {
+        local::secure_dir(dir, 0o700)?;
+    }
+
+    local::write_atomic(path, text.as_bytes(), 0o600)
+}
+
+impl TokenStore {
+    pub fn new(path: &str) -> TokenStore {
+        TokenStore {
+            path: path.to_string(),
+            cache: Mutex::new((None, Vec::new())),
+        }
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Identity> {
+        let mut cache = self.cache.lock().unwrap();
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+
+        if modified != cache.0 {
+            match read(&self.path) {
+                Ok(file) => *cache = (modified, file.tokens),
+                // keep the tokens we had rather than locking everyone out
+                // over a half written file
+                Err(e) => println!("[HTTP]: Could not read tokens: {}", e),
+            }
+        }
+
+        let hash = hash(token);
+
+        cache
+            .1
+            .iter()
+            .find(|entry| entry.hash == hash)
+            .map(|entry| Identity {
+                name: entry.name.clone(),
+                role: entry.role,
+            })
+    }
+}
+
+// Creates a token and returns it. It is only ever shown this once.
+pub fn add_token(path: &str, name: &str, role: Role) -> Result<String> {
+    let mut file = read(path)?;
+
+    if name.is_empty() {
+        return Err(anyhow!("token name must not be empty"));
+    }
+
+    if file.tokens.iter().any(|entry| entry.name == name) {
+        return Err(anyhow!("a token named '{}' already exists", name));
+    }
+
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let token = format!("snt_{}", hex::encode(secret));
+
+    file.tokens.push(TokenEntry {
+        name: name.to_string(),
+        role,
+        hash: hash(&token),
+        created: unix_time(),
+    });
+
+    write(path, &file)?;
+    Ok(token)
+}
+
+pub fn list_tokens(path: &str) -> Result<Vec<TokenEntry>> {
+    Ok(read(path)?.tokens)
+}
+
+// Returns false if there was no such token.
+pub fn remove_token(path: &str, name: &str) -> Result<bool> {
+    let mut file = read(path)?;
+    let before = file.tokens.len();
+
+    file.tokens.retain(|entry| entry.name != name);
+
+    if file.tokens.len() == before {
+        return Ok(false);
+    }
+
+    write(path, &file)?;
+    Ok(true)
+}
+
+// `sentineld token ...`, run against the token file directly, so it works
+// whether or not the daemon is up.
+pub fn run_command(path: &str, command: &TokenCommand) -> Result<()> {
+    match command {
+        TokenCommand::Add { name, role } => {
+            let token = add_token(path, name, *role)?;
+            println!("{}", token);
+            eprintln!(
+                "Token '{}' ({}) added to {}; it is not shown again",
+                name,
+                role.as_str(),
+                path
+            );
+        }
+        TokenCommand::List => {
+            for entry in list_tokens(path)? {
+                println!(
+                    "{:<24} {:<12} created {}",
+                    entry.name,
+                    entry.role.as_str(),
+                    entry.created
+                );
+            }
+        }
+        TokenCommand::Remove { name } => {
+            if !remove_token(path, name)? {
+                return Err(anyhow!("no token named '{}' in {}", name, path));
+            }
+            println!("Token '{}' removed", name);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("sentineld-auth-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("tokens.json").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn listed_routes_need_their_role() {
+        assert_eq!(required_role("GET", "/clients"), Role::Viewer);
+        assert_eq!(required_role("DELETE", "/clients/{id}"), Role::Invigilator);
+        assert_eq!(required_role("POST", "/send"), Role::Invigilator);
+    }
+
+    #[test]
+    fn unlisted_routes_are_admin_only() {
+        assert_eq!(required_role("POST", "/stop"), Role::Admin);
+        assert_eq!(required_role("GET", "/audit"), Role::Admin);
+        // the method matters, not just the route
+        assert_eq!(required_role("PUT", "/clients"), Role::Admin);
+    }
+
+    #[test]
+    fn authenticates_added_tokens_only() {
+        let path = temp_path("add");
+        let token = add_token(&path, "lab", Role::Invigilator).unwrap();
+        let store = TokenStore::new(&path);
+
+        let identity = store.authenticate(&token).unwrap();
+        assert_eq!(identity.name, "lab");
+        assert_eq!(identity.role, Role::Invigilator);
+
+        assert!(store.authenticate("snt_wrong").is_none());
+        assert!(add_token(&path, "lab", Role::Admin).is_err());
+    }
+
+    #[test]
+    fn removed_tokens_stop_working() {
+        let path = temp_path("remove");
+        let token = add_token(&path, "lab", Role::Viewer).unwrap();
+        let store = TokenStore::new(&path);
+        assert!(store.authenticate(&token).is_some());
+
+        assert!(remove_token(&path, "lab").unwrap());
+        assert!(!remove_token(&path, "lab").unwrap());
+
+        // the store notices the file changed; make sure the mtime moves
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(later))
+            .unwrap();
+
+        assert!(store.authenticate(&token).is_none());
+    }
+
+    #[test]
+    fn refuses_a_file_others_can_write() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("mode");
+        add_token(&path, "lab", Role::Viewer).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+
+        assert!(list_tokens(&path).is_err());
+    }
+}
@@ -8,12 +8,14 @@ use rdkafka::{
 use serde::Deserialize;
 use uuid::Uuid;
 
+use crate::config::KafkaConfig;
+
 #[derive(Deserialize)]
 struct SubscribeRequest {
     topic: String,
 }
 
-pub async fn handle_ws(mut socket: WebSocket, ip_addr: String) {
+pub async fn handle_ws(mut socket: WebSocket, kafka: KafkaConfig) {
     println!("WebSocket connected");
 
     let msg = match socket.next().await {
@@ -34,8 +36,8 @@ pub async fn handle_ws(mut socket: WebSocket, ip_addr: String) {
 
     println!("Subscribing to topic: {}", sub.topic);
 
-    let group_id = format!("sentinel-ws-{}", Uuid::new_v4());
-    let server = format!("{}:9092", ip_addr);
+    let group_id = format!("{}-{}", kafka.group_prefix, Uuid::new_v4());
+    let server = kafka.brokers.unwrap_or_default();
 
     let consumer: StreamConsumer = match ClientConfig::new()
         .set("bootstrap.servers", server)
@@ -0,0 +1,148 @@
+use axum::extract::ws::{Message as WsMessage, WebSocket};
+use axum::response::sse::Event as SseEvent;
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::events::Event;
+use crate::groups::Groups;
+use crate::{unix_time, Clients};
+
+// `?client=27` and `?group=Lab-3`; with neither every event is sent.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EventFilter {
+    pub client: Option<usize>,
+    pub group: Option<String>,
+}
+
+// An event as sent to subscribers, stamped with the time it went out.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    at: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+// One subscriber's filtered view of the event bus.
+pub struct Feed {
+    rx: broadcast::Receiver<Event>,
+    filter: EventFilter,
+    groups: Groups,
+    // where each client connects from, for groups defined by subnet; taken
+    // from the client table at the start and kept up to date from events,
+    // so a client still matches its group after it disconnects
+    addrs: HashMap<usize, String>,
+}
+
+impl Feed {
+    // `rx` is subscribed before the table is read, so nothing falls in
+    // between.
+    pub async fn new(
+        rx: broadcast::Receiver<Event>,
+        filter: EventFilter,
+        groups: Groups,
+        clients: &Clients,
+    ) -> Feed {
+        let addrs = clients
+            .lock()
+            .await
+            .iter()
+            .map(|(id, meta)| (*id, meta.addr.clone()))
+            .collect();
+
+        Feed {
+            rx,
+            filter,
+            groups,
+            addrs,
+        }
+    }
+
+    // The next event that passes the filter, as JSON. A subscriber too slow
+    // to keep up gets a "lagged" event with the number it missed, and should
+    // fetch /clients again. None once the bus is gone.
+    pub async fn next(&mut self) -> Option<String> {
+        loop {
+            let event = match self.rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    let lagged = serde_json::json!({
+                        "event": "lagged",
+                        "at": unix_time(),
+                        "missed": missed,
+                    });
+                    return Some(lagged.to_string());
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            if let Event::Connected { id, addr, .. } = &event {
+                self.addrs.insert(*id, addr.clone());
+            }
+
+            if self.wants(&event).await {
+                let envelope = Envelope {
+                    at: unix_time(),
+                    event: &event,
+                };
+
+                return serde_json::to_string(&envelope).ok();
+            }
+        }
+    }
+
+    async fn wants(&self, event: &Event) -> bool {
+        let id = event.client();
+
+        if self.filter.client.is_some_and(|client| client != id) {
+            return false;
+        }
+
+        let Some(name) = &self.filter.group else {
+            return true;
+        };
+
+        let addr = self.addrs.get(&id).map_or("", String::as_str);
+
+        self.groups
+            .lock()
+            .await
+            .get(name)
+            .is_some_and(|rules| rules.matches(id, addr))
+    }
+}
+
+// One text frame per event, until either side goes away.
+pub async fn websocket(mut socket: WebSocket, mut feed: Feed) {
+    loop {
+        tokio::select! {
+            text = feed.next() => {
+                let Some(text) = text else {
+                    let _ = socket.send(WsMessage::Close(None)).await;
+                    return;
+                };
+
+                if socket.send(WsMessage::Text(text.into())).await.is_err() {
+                    return;
+                }
+            }
+
+            // nothing is expected from the subscriber; this only notices
+            // it leaving
+            msg = socket.recv() => match msg {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+// One `data:` line per event.
+pub fn sse(feed: Feed) -> impl Stream<Item = Result<SseEvent, Infallible>> {
+    stream::unfold(feed, |mut feed| async move {
+        let text = feed.next().await?;
+        Some((Ok(SseEvent::default().data(text)), feed))
+    })
+}
@@ -1,24 +1,59 @@
 use anyhow::Result;
-use axum::http::Method;
+use axum::extract::{ConnectInfo, FromRequestParts, MatchedPath, Request};
+use axum::http::{header, request::Parts, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{KeepAlive, Sse};
+use axum::response::Response;
 use axum::{extract::ws::WebSocketUpgrade, response::IntoResponse};
 use axum::{
-    extract::State,
-    routing::{get, post},
+    extract::{Path, Query, State},
+    routing::{get, post, put},
     Json, Router,
 };
+use sentinel_protocol::local::{self, PeerCred};
+use sentinel_protocol::reload::{log_report, ReloadReport};
+use sentinel_protocol::{Action, Capability, Message, Policy};
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::broadcast;
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
+use crate::attendance;
+use crate::audit::{AuditEntry, AuditQuery, Caller, SharedAudit};
+use crate::auth::{self, Identity, Role, SharedTokens};
+use crate::bridge::live::{self, EventFilter, Feed};
+use crate::bridge::unix;
 use crate::config::SharedConfig;
-use crate::Clients;
+use crate::events::{DisconnectReason, EventBus};
+use crate::groups::{GroupRules, GroupStore, Groups, Source};
+use crate::jobs::{Job, JobStatus, Jobs};
+use crate::logfiles::{self, LogFiles};
+use crate::policy::{self, Policies, PolicyStatus, Scope};
+use crate::registry::{
+    blocking, KnownClient, LoginFilter, LoginRecord, SessionRecord, SharedRegistry, UserRecord,
+    Window,
+};
+use crate::reload::Reloader;
+use crate::{unix_time, ClientMeta, Clients};
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: SharedConfig,
-    clients: Clients,
-    shutdown: broadcast::Sender<()>,
+    pub clients: Clients,
+    pub jobs: Jobs,
+    pub policies: Policies,
+    pub groups: Groups,
+    pub registry: SharedRegistry,
+    pub logs: LogFiles,
+    pub audit: SharedAudit,
+    pub tokens: SharedTokens,
+    pub events: EventBus,
+    pub reloader: Arc<Reloader>,
+    pub shutdown: broadcast::Sender<()>,
 }
 
 #[derive(Serialize)]
@@ -26,53 +61,584 @@ struct ApiResponse {
     message: String,
 }
 
+// Every failed request gets its status code and a body of
+// {"status": 404, "error": "Not Found", "message": "Client not found"}.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    status: u16,
+    error: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn not_found(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, message)
+    }
+
+    fn conflict(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::CONFLICT, message)
+    }
+
+    fn internal(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    fn registry(e: anyhow::Error) -> ApiError {
+        ApiError::internal(format!("Registry error: {}", e))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            status: self.status.as_u16(),
+            error: self.status.canonical_reason().unwrap_or("Error"),
+            message: self.message,
+        };
+
+        (self.status, Json(body)).into_response()
+    }
+}
+
+// Gives the errors axum produces itself, such as unknown routes or a body
+// that does not parse, the same JSON body as ours.
+async fn json_errors(response: Response) -> Response {
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return response;
+    }
+
+    let body = axum::body::to_bytes(response.into_body(), 64 * 1024)
+        .await
+        .unwrap_or_default();
+    let message = String::from_utf8_lossy(&body).trim().to_string();
+
+    if message.is_empty() {
+        ApiError::new(status, status.canonical_reason().unwrap_or("Error")).into_response()
+    } else {
+        ApiError::new(status, message).into_response()
+    }
+}
+
 #[derive(Deserialize)]
 struct SendRequest {
     id: usize,
     message: String,
+    // false returns the job right away, to be polled on /jobs/{id}
+    #[serde(default = "default_wait")]
+    wait: bool,
+}
+
+fn default_wait() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+struct SendResponse {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<Job>,
+}
+
+impl SendResponse {
+    fn error(message: impl Into<String>) -> SendResponse {
+        SendResponse {
+            message: message.into(),
+            job: None,
+        }
+    }
+}
+
+// 200 once the command has finished, 202 while it is queued or running.
+type Sent = (StatusCode, Json<SendResponse>);
+
+// Body of POST /clients/{id}/commands.
+#[derive(Deserialize)]
+struct CommandRequest {
+    command: String,
+    #[serde(default = "default_wait")]
+    wait: bool,
+}
+
+// A client as listed on /clients: connected ones from the live table, the
+// others from the registry.
+#[derive(Serialize)]
+struct ClientView {
+    id: usize,
+    online: bool,
+    name: String,
+    register: String,
+    addr: String,
+    version: String,
+    // unix seconds of the last frame received
+    last_seen: u64,
+    groups: Vec<String>,
+    #[serde(flatten)]
+    live: Option<LiveView>,
+}
+
+// Only known while the client is connected.
+#[derive(Serialize)]
+struct LiveView {
+    session: u64,
+    authenticated: bool,
+    protocol: u32,
+    capabilities: Vec<Capability>,
+    rtt_ms: Option<u64>,
+    policy: PolicyStatus,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientState {
+    #[default]
+    Online,
+    Offline,
+    All,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientSort {
+    #[default]
+    Id,
+    Name,
+    Regno,
+    LastSeen,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+struct ClientQuery {
+    // case-insensitive parts of the student's name and register number
+    name: Option<String>,
+    regno: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    state: ClientState,
+    #[serde(default)]
+    sort: ClientSort,
+    #[serde(default)]
+    order: Order,
+    #[serde(default)]
+    offset: usize,
+    // everything after `offset` by default
+    limit: Option<usize>,
+}
+
+// Number of clients matching the query, before offset and limit.
+const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+// Body of PUT /policies/..., a policy plus the members when it is a group's.
+#[derive(Deserialize)]
+struct PolicyRequest {
+    #[serde(default)]
+    members: Vec<usize>,
+    #[serde(flatten)]
+    policy: Policy,
+}
+
+#[derive(Serialize)]
+struct PolicyUpdate {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<u64>,
+    // connected clients the change was sent to
+    pushed: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    // every connected client if None
+    group: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Delivery {
+    id: usize,
+    // what /send would have answered with
+    status: u16,
+    #[serde(flatten)]
+    response: SendResponse,
+}
+
+#[derive(Serialize)]
+struct BroadcastResponse {
+    message: String,
+    results: Vec<Delivery>,
+}
+
+#[derive(Serialize)]
+struct GroupView {
+    name: String,
+    source: Source,
+    #[serde(flatten)]
+    rules: GroupRules,
+    // connected members
+    online: Vec<usize>,
+}
+
+#[derive(Serialize)]
+struct GroupUpdate {
+    message: String,
+    online: Vec<usize>,
+    // clients whose policy changed with the membership
+    pushed: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    // unix seconds, the whole history by default
+    since: Option<u64>,
+    until: Option<u64>,
+    // most recent entries of each kind, at most MAX_HISTORY
+    limit: Option<usize>,
+}
+
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Serialize)]
+struct History {
+    client: KnownClient,
+    online: bool,
+    sessions: Vec<SessionRecord>,
+    users: Vec<UserRecord>,
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    // unix seconds, the whole history by default
+    since: Option<u64>,
+    until: Option<u64>,
+    client: Option<usize>,
+    regno: Option<String>,
+    // oldest entries first, at most MAX_HISTORY
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct AttendanceQuery {
+    // unix seconds, from the first login up to now by default
+    since: Option<u64>,
+    until: Option<u64>,
+    // "json" (default) or "csv"
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TailQuery {
+    #[serde(default = "default_tail")]
+    lines: usize,
+}
+
+fn default_tail() -> usize {
+    100
+}
+
+const MAX_TAIL: usize = 5000;
+
+#[derive(Serialize)]
+struct LogTail {
+    client: usize,
+    lines: Vec<String>,
+}
+
+fn caller(parts: &Parts) -> Caller {
+    let tcp = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+    let local = || {
+        parts
+            .extensions
+            .get::<ConnectInfo<PeerCred>>()
+            .map(|ConnectInfo(peer)| peer.describe())
+    };
+    let addr = tcp.or_else(local).unwrap_or_else(|| "unknown".into());
+
+    let actor = parts
+        .extensions
+        .get::<Identity>()
+        .map_or_else(|| "anonymous".into(), |identity| identity.name.clone());
+
+    Caller { actor, addr }
+}
+
+// The identity authorize() put on the request.
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Caller, Infallible> {
+        Ok(caller(parts))
+    }
+}
+
+// Streams a browser opens with WebSocket or EventSource, neither of which
+// can set headers.
+const QUERY_TOKEN_ROUTES: &[&str] = &["/events", "/events/ws", "/kafka/ws"];
+
+// `Authorization: Bearer <token>`, or `?token=` on the streaming routes
+// alone. Anywhere else a token in the URL would end up in proxy logs and
+// browser history for nothing, so it is not looked at.
+fn bearer(parts: &Parts) -> Option<String> {
+    let header = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let query = || {
+        if !QUERY_TOKEN_ROUTES.contains(&parts.uri.path()) {
+            return None;
+        }
+
+        parts
+            .uri
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    };
+
+    header.or_else(query).map(|token| token.trim().to_string())
+}
+
+// Finds out who is calling and refuses routes above their role. A local
+// user on the Unix socket is known by SO_PEERCRED, anyone else needs a
+// token. With api.auth off every caller is an anonymous admin.
+async fn authorize(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+
+    let (enabled, socket_users) = {
+        let cfg = state.config.lock().await;
+        (cfg.api.auth, cfg.api.socket_users.clone())
+    };
+
+    let local = parts
+        .extensions
+        .get::<ConnectInfo<PeerCred>>()
+        .and_then(|ConnectInfo(peer)| unix::identity(peer, &socket_users));
+
+    let identity = if enabled {
+        local.or_else(|| bearer(&parts).and_then(|token| state.tokens.authenticate(&token)))
+    } else {
+        Some(Identity {
+            name: "anonymous".into(),
+            role: Role::Admin,
+        })
+    };
+
+    let route = parts
+        .extensions
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| parts.uri.path().to_string());
+    let required = auth::required_role(parts.method.as_str(), &route);
+
+    let refused = match &identity {
+        None => Some((
+            StatusCode::UNAUTHORIZED,
+            "Missing or unknown API token".to_string(),
+        )),
+        Some(identity) if identity.role < required => Some((
+            StatusCode::FORBIDDEN,
+            format!(
+                "{} {} needs the {} role",
+                parts.method,
+                route,
+                required.as_str()
+            ),
+        )),
+        Some(_) => None,
+    };
+
+    if let Some(identity) = identity {
+        parts.extensions.insert(identity);
+    }
+
+    if let Some((status, message)) = refused {
+        state.audit.record(
+            &caller(&parts),
+            "denied",
+            Vec::new(),
+            false,
+            message.clone(),
+        );
+
+        return ApiError::new(status, message).into_response();
+    }
+
+    next.run(Request::from_parts(parts, body)).await
+}
+
+// With no origins configured no cross-origin request is allowed, so a page
+// from another site cannot call the API through the admin's browser.
+fn cors(origins: &[String]) -> CorsLayer {
+    let origins: Vec<HeaderValue> = origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect();
+
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+        .expose_headers([TOTAL_COUNT])
+}
+
+// Subscribes before anything else can happen, so no event is missed
+// between the checks and the stream starting.
+async fn feed(state: &AppState, filter: EventFilter) -> Result<Feed, ApiError> {
+    let rx = state.events.subscribe();
+
+    if let Some(name) = &filter.group {
+        if state.groups.lock().await.get(name).is_none() {
+            return Err(ApiError::not_found(format!("Group '{}' not found", name)));
+        }
+    }
+
+    Ok(Feed::new(rx, filter, state.groups.clone(), &state.clients).await)
+}
+
+async fn events_sse(
+    State(state): State<AppState>,
+    Query(filter): Query<EventFilter>,
+) -> Result<Response, ApiError> {
+    let feed = feed(&state, filter).await?;
+
+    Ok(Sse::new(live::sse(feed))
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+async fn events_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(filter): Query<EventFilter>,
+) -> Result<Response, ApiError> {
+    let feed = feed(&state, filter).await?;
+
+    Ok(ws.on_upgrade(|socket| live::websocket(socket, feed)))
 }
 
 async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
-    let ip_addr = state.config.lock().await.server_ip.clone();
+    let kafka = state.config.lock().await.kafka.clone();
 
     ws.on_upgrade(|socket| async move {
-        crate::bridge::kafka_ws::handle_ws(socket, ip_addr).await;
+        crate::bridge::kafka_ws::handle_ws(socket, kafka).await;
     })
 }
 
-pub async fn start_http(
-    config: SharedConfig,
-    clients: Clients,
-    shutdown_tx: broadcast::Sender<()>,
-    mut shutdown: broadcast::Receiver<()>,
-) -> Result<()> {
-    let state = AppState {
-        config,
-        clients,
-        shutdown: shutdown_tx.clone(),
+pub async fn start_http(state: AppState, mut shutdown: broadcast::Receiver<()>) -> Result<()> {
+    let (http_addr, origins, socket, socket_mode) = {
+        let cfg = state.config.lock().await;
+        (
+            cfg.server.http.clone(),
+            cfg.api.cors_origins.clone(),
+            cfg.api.socket.clone(),
+            cfg.api.socket_mode,
+        )
     };
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers(Any);
-
     let app = Router::new()
         .route("/status", get(status))
         .route("/clients", get(list_clients))
+        .route("/clients/offline", get(offline_clients))
+        .route("/clients/{id}", get(get_client).delete(kick_client))
+        .route("/clients/{id}/commands", post(client_command))
+        .route("/clients/{id}/history", get(client_history))
+        .route("/clients/{id}/log", get(client_log))
+        .route("/logins", get(list_logins))
+        .route("/attendance", get(attendance_report))
         .route("/send", post(send_message))
+        .route("/broadcast", post(broadcast))
+        .route("/groups", get(list_groups))
+        .route("/groups/{name}", put(put_group).delete(delete_group))
+        .route("/jobs/{id}", get(get_job))
         .route("/stop", post(stop_server))
+        .route("/admin/reload", post(reload_config))
+        .route("/audit", get(query_audit))
+        .route("/policies", get(list_policies))
+        .route("/policies/default", put(put_default).delete(delete_default))
+        .route(
+            "/policies/{kind}/{name}",
+            put(put_policy).delete(delete_policy),
+        )
         .route("/kafka/ws", get(ws_handler))
+        .route("/events", get(events_sse))
+        .route("/events/ws", get(events_ws))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
         .with_state(state)
-        .layer(cors);
+        .layer(middleware::map_response(json_errors))
+        .layer(cors(&origins));
+
+    let listener = TcpListener::bind(&http_addr).await?;
+
+    println!("[HTTP]: server listening on {}", http_addr);
 
-    let listener = TcpListener::bind("127.0.0.1:3737").await?;
+    // A socket that cannot be bound is not worth stopping the server for;
+    // the TCP API keeps working.
+    let bound = if socket.is_empty() {
+        None
+    } else {
+        match local::bind(&socket, socket_mode) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                println!("[HTTP]: Unix socket disabled: {:#}", e);
+                None
+            }
+        }
+    };
 
-    println!("[HTTP]: server listening on 127.0.0.1:3737");
+    let local = bound.map(|listener| {
+        println!("[HTTP]: server listening on {}", socket);
+
+        let app = app.clone();
+        tokio::spawn(async move {
+            let served = axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<PeerCred>(),
+            )
+            .await;
+
+            if let Err(e) = served {
+                println!("[HTTP]: Unix socket server failed: {}", e);
+            }
+        })
+    });
 
     tokio::select! {
 
-        result = axum::serve(listener, app) => {
+        result = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()) => {
             result?;
         }
 
@@ -81,6 +647,11 @@ pub async fn start_http(
         }
     }
 
+    if let Some(local) = local {
+        local.abort();
+        let _ = std::fs::remove_file(&socket);
+    }
+
     Ok(())
 }
 
@@ -90,46 +661,917 @@ async fn status() -> Json<ApiResponse> {
     })
 }
 
-async fn list_clients(State(state): State<AppState>) -> Json<serde_json::Value> {
-    let guard = state.clients.lock().await;
+fn online_view(id: usize, meta: &ClientMeta, groups: &GroupStore) -> ClientView {
+    ClientView {
+        id,
+        online: true,
+        name: meta.name.clone(),
+        register: meta.reg.clone(),
+        addr: meta.addr.clone(),
+        version: meta.version.clone(),
+        last_seen: meta.last_seen,
+        groups: groups.names_for(id, &meta.addr),
+        live: Some(LiveView {
+            session: meta.session,
+            authenticated: meta.authenticated,
+            protocol: meta.protocol,
+            capabilities: meta.capabilities.clone(),
+            rtt_ms: meta.rtt_ms,
+            policy: meta.policy.clone(),
+        }),
+    }
+}
 
-    let list: Vec<_> = guard
-        .iter()
-        .map(|(id, meta)| {
-            serde_json::json!({
-                "id": id,
-                "name": meta.name,
-                "register": meta.reg
-            })
-        })
+fn offline_view(client: KnownClient, groups: &GroupStore) -> ClientView {
+    ClientView {
+        groups: groups.names_for(client.id, &client.last_addr),
+        id: client.id,
+        online: false,
+        name: client.name,
+        register: client.regno,
+        addr: client.last_addr,
+        version: client.version,
+        last_seen: client.last_seen,
+        live: None,
+    }
+}
+
+fn contains_ignoring_case(value: &str, part: &str) -> bool {
+    value.to_lowercase().contains(&part.to_lowercase())
+}
+
+// Connected clients by default; `state=offline` or `state=all` adds the
+// ones the registry knows. The total before paging is in X-Total-Count.
+async fn list_clients(
+    State(state): State<AppState>,
+    Query(query): Query<ClientQuery>,
+) -> Result<Response, ApiError> {
+    let known = match query.state {
+        ClientState::Online => Vec::new(),
+        _ => blocking(&state.registry, |r| r.known())
+            .await
+            .map_err(ApiError::registry)?,
+    };
+
+    let mut list: Vec<ClientView> = {
+        let groups = state.groups.lock().await;
+
+        if let Some(name) = &query.group {
+            if groups.get(name).is_none() {
+                return Err(ApiError::not_found(format!("Group '{}' not found", name)));
+            }
+        }
+
+        let clients = state.clients.lock().await;
+
+        let online = clients
+            .iter()
+            .filter(|_| query.state != ClientState::Offline)
+            .map(|(id, meta)| online_view(*id, meta, &groups));
+
+        let offline = known
+            .into_iter()
+            .filter(|client| !clients.contains_key(&client.id))
+            .map(|client| offline_view(client, &groups));
+
+        online.chain(offline).collect()
+    };
+
+    list.retain(|client| {
+        query
+            .name
+            .as_deref()
+            .is_none_or(|name| contains_ignoring_case(&client.name, name))
+            && query
+                .regno
+                .as_deref()
+                .is_none_or(|regno| contains_ignoring_case(&client.register, regno))
+            && query
+                .group
+                .as_ref()
+                .is_none_or(|group| client.groups.contains(group))
+    });
+
+    list.sort_by(|a, b| {
+        let by = match query.sort {
+            ClientSort::Id => a.id.cmp(&b.id),
+            ClientSort::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            ClientSort::Regno => a.register.to_lowercase().cmp(&b.register.to_lowercase()),
+            ClientSort::LastSeen => a.last_seen.cmp(&b.last_seen),
+        };
+
+        by.then(a.id.cmp(&b.id))
+    });
+
+    if query.order == Order::Desc {
+        list.reverse();
+    }
+
+    let total = list.len();
+    let page: Vec<ClientView> = list
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
         .collect();
 
-    Json(serde_json::json!(list))
+    Ok(([(TOTAL_COUNT, total.to_string())], Json(page)).into_response())
+}
+
+async fn get_client(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<ClientView>, ApiError> {
+    {
+        let groups = state.groups.lock().await;
+        let clients = state.clients.lock().await;
+
+        if let Some(meta) = clients.get(&id) {
+            return Ok(Json(online_view(id, meta, &groups)));
+        }
+    }
+
+    let known = blocking(&state.registry, move |r| r.client(id))
+        .await
+        .map_err(ApiError::registry)?
+        .ok_or_else(|| ApiError::not_found("Client not found"))?;
+
+    Ok(Json(offline_view(known, &*state.groups.lock().await)))
+}
+
+// Hangs up on the client, which fails its running commands. Its sentry
+// connects again by itself, so this mostly forces a fresh session.
+async fn kick_client(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<usize>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    let removed = state.clients.lock().await.remove(&id);
+
+    let Some(meta) = removed else {
+        state
+            .audit
+            .record(&caller, "kick", vec![id], false, "not connected");
+        return Err(ApiError::not_found("Client not connected"));
+    };
+
+    let _ = meta.close.send(DisconnectReason::Kicked);
+    println!("[HTTP]: Client {} disconnected by {}", id, caller.actor);
+
+    state
+        .audit
+        .record(&caller, "kick", vec![id], true, meta.addr);
+
+    Ok(Json(ApiResponse {
+        message: format!("Client {} disconnected", id),
+    }))
+}
+
+// Clients the registry knows about that are not connected right now.
+async fn offline_clients(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<KnownClient>>, ApiError> {
+    let known = blocking(&state.registry, |r| r.known())
+        .await
+        .map_err(ApiError::registry)?;
+
+    let online = state.clients.lock().await;
+
+    Ok(Json(
+        known
+            .into_iter()
+            .filter(|client| !online.contains_key(&client.id))
+            .collect(),
+    ))
+}
+
+async fn client_history(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<History>, ApiError> {
+    let window = Window {
+        since: query.since.unwrap_or(0),
+        until: query.until.unwrap_or(i64::MAX as u64),
+        limit: query.limit.unwrap_or(100).min(MAX_HISTORY),
+    };
+
+    let found = blocking(&state.registry, move |r| {
+        let Some(client) = r.client(id)? else {
+            return Ok(None);
+        };
+
+        Ok(Some((
+            client,
+            r.sessions(id, window)?,
+            r.users(id, window)?,
+        )))
+    })
+    .await
+    .map_err(ApiError::registry)?;
+
+    let Some((client, sessions, users)) = found else {
+        return Err(ApiError::not_found("Client not found"));
+    };
+
+    let online = state.clients.lock().await.contains_key(&id);
+
+    Ok(Json(History {
+        client,
+        online,
+        sessions,
+        users,
+    }))
+}
+
+async fn list_logins(
+    State(state): State<AppState>,
+    Query(query): Query<LoginQuery>,
+) -> Result<Json<Vec<LoginRecord>>, ApiError> {
+    let filter = LoginFilter {
+        since: query.since.unwrap_or(0),
+        until: query.until.unwrap_or(i64::MAX as u64),
+        client: query.client,
+        regno: query.regno,
+        limit: Some(query.limit.unwrap_or(100).min(MAX_HISTORY)),
+    };
+
+    blocking(&state.registry, move |r| r.logins(&filter))
+        .await
+        .map(Json)
+        .map_err(ApiError::registry)
+}
+
+async fn attendance_report(
+    State(state): State<AppState>,
+    Query(query): Query<AttendanceQuery>,
+) -> Result<Response, ApiError> {
+    let since = query.since.unwrap_or(0);
+    let until = query.until.unwrap_or_else(unix_time);
+
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => {
+            return Err(ApiError::bad_request(format!(
+                "Unknown format '{}', expected json or csv",
+                other
+            )))
+        }
+    };
+
+    let filter = LoginFilter {
+        since,
+        until,
+        ..LoginFilter::default()
+    };
+
+    let logins = blocking(&state.registry, move |r| r.logins(&filter))
+        .await
+        .map_err(ApiError::registry)?;
+
+    let report = attendance::report(&logins, since, until);
+
+    Ok(if csv {
+        (
+            [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+            attendance::csv(&report),
+        )
+            .into_response()
+    } else {
+        Json(report).into_response()
+    })
+}
+
+// Last lines of the client's current log file, oldest first.
+async fn client_log(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Query(query): Query<TailQuery>,
+) -> Result<Json<LogTail>, ApiError> {
+    let path = state.logs.path(id);
+    let lines = query.lines.min(MAX_TAIL);
+
+    let tail = tokio::task::spawn_blocking(move || logfiles::tail(&path, lines))
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|tail| tail);
+
+    match tail {
+        Ok(lines) => Ok(Json(LogTail { client: id, lines })),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(ApiError::not_found("No log for this client"))
+        }
+        Err(e) => Err(ApiError::internal(format!("Could not read log: {}", e))),
+    }
 }
 
 async fn send_message(
     State(state): State<AppState>,
+    caller: Caller,
     Json(req): Json<SendRequest>,
-) -> Json<ApiResponse> {
-    let guard = state.clients.lock().await;
+) -> Result<Sent, ApiError> {
+    send_audited(&state, &caller, req).await
+}
+
+// Same as /send, with the client in the path.
+async fn client_command(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<usize>,
+    Json(req): Json<CommandRequest>,
+) -> Result<Sent, ApiError> {
+    let req = SendRequest {
+        id,
+        message: req.command,
+        wait: req.wait,
+    };
+
+    send_audited(&state, &caller, req).await
+}
+
+async fn send_audited(
+    state: &AppState,
+    caller: &Caller,
+    req: SendRequest,
+) -> Result<Sent, ApiError> {
+    let (id, command) = (req.id, req.message.clone());
+    let sent = send(state, req).await;
+
+    let (ok, detail) = match &sent {
+        Ok((_, Json(response))) => {
+            let ok = response.job.as_ref().is_some_and(|job| {
+                !matches!(
+                    job.status,
+                    JobStatus::Failed { .. } | JobStatus::Completed { ok: false, .. }
+                )
+            });
+
+            (ok, response.message.clone())
+        }
+        Err(e) => (false, e.message.clone()),
+    };
+
+    state.audit.record(
+        caller,
+        "send",
+        vec![id],
+        ok,
+        format!("{}: {}", command, detail),
+    );
+
+    sent
+}
+
+async fn send(state: &AppState, req: SendRequest) -> Result<Sent, ApiError> {
+    let mut action: Action = req
+        .message
+        .parse()
+        .map_err(|e| ApiError::bad_request(format!("Invalid command: {}", e)))?;
+
+    let (tx, session) = {
+        let guard = state.clients.lock().await;
+
+        let Some(client) = guard.get(&req.id) else {
+            return Err(ApiError::not_found("Client not found"));
+        };
+
+        if let Some(cap) = action.required_capability() {
+            if !client.capabilities.contains(&cap) {
+                return Err(ApiError::conflict(format!(
+                    "Client {} does not support {}",
+                    req.id, cap
+                )));
+            }
+        }
+
+        (client.tx.clone(), client.session)
+    };
 
-    if let Some(client) = guard.get(&req.id) {
-        let _ = client.tx.send(req.message.clone()).await;
+    let timeout = Duration::from_secs(state.config.lock().await.clients.command_timeout);
 
-        Json(ApiResponse {
-            message: "Message sent".into(),
+    let (job_id, waiter) = {
+        let mut jobs = state.jobs.lock().await;
+        let job_id = jobs
+            .create(req.id, session, action.to_string())
+            .map_err(|e| ApiError::new(StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
+        let waiter = req.wait.then(|| jobs.wait(job_id));
+        (job_id, waiter)
+    };
+
+    action.id = job_id;
+
+    // the session ended between the lookup and the send; the command
+    // never left, whether or not the caller meant to wait for it
+    if tx.send(Message::Action(action)).await.is_err() {
+        let mut jobs = state.jobs.lock().await;
+        jobs.fail(session, job_id, "client disconnected");
+        jobs.cancel_wait(job_id);
+
+        return Ok((
+            StatusCode::GONE,
+            Json(SendResponse {
+                message: format!("Client {} disconnected, command not sent", req.id),
+                job: jobs.get(job_id),
+            }),
+        ));
+    }
+
+    let Some(waiter) = waiter else {
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(SendResponse {
+                message: "Command queued".into(),
+                job: state.jobs.lock().await.get(job_id),
+            }),
+        ));
+    };
+
+    match tokio::time::timeout(timeout, waiter).await {
+        Ok(Ok(job)) => {
+            let message = match job.status {
+                JobStatus::Completed { ok: true, .. } => "Command completed",
+                _ => "Command failed",
+            };
+
+            Ok((
+                StatusCode::OK,
+                Json(SendResponse {
+                    message: message.into(),
+                    job: Some(job),
+                }),
+            ))
+        }
+        _ => {
+            let mut jobs = state.jobs.lock().await;
+            jobs.cancel_wait(job_id);
+
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(SendResponse {
+                    message: format!("Command timed out, poll /jobs/{}", job_id),
+                    job: jobs.get(job_id),
+                }),
+            ))
+        }
+    }
+}
+
+// Sends the command to every target at once and waits for all of them.
+// Explicit group ids that are not connected are reported as such; clients
+// matched only by a range or subnet have to be connected.
+async fn broadcast(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(req): Json<BroadcastRequest>,
+) -> Result<Json<BroadcastResponse>, ApiError> {
+    let targets: Vec<usize> = {
+        let groups = state.groups.lock().await;
+        let clients = state.clients.lock().await;
+
+        match &req.group {
+            None => clients.keys().copied().collect(),
+            Some(name) => {
+                let Some(rules) = groups.get(name) else {
+                    return Err(ApiError::not_found(format!("Group '{}' not found", name)));
+                };
+
+                clients
+                    .iter()
+                    .filter(|(id, meta)| rules.matches(**id, &meta.addr))
+                    .map(|(id, _)| *id)
+                    .chain(rules.ids.iter().copied())
+                    .collect()
+            }
+        }
+    };
+
+    let mut targets = targets;
+    targets.sort_unstable();
+    targets.dedup();
+
+    let sends = targets.iter().map(|id| {
+        send(
+            &state,
+            SendRequest {
+                id: *id,
+                message: req.message.clone(),
+                wait: true,
+            },
+        )
+    });
+
+    let results: Vec<Delivery> = futures_util::future::join_all(sends)
+        .await
+        .into_iter()
+        .zip(&targets)
+        .map(|(sent, id)| match sent {
+            Ok((status, Json(response))) => Delivery {
+                id: *id,
+                status: status.as_u16(),
+                response,
+            },
+            Err(e) => Delivery {
+                id: *id,
+                status: e.status.as_u16(),
+                response: SendResponse::error(e.message),
+            },
         })
-    } else {
-        Json(ApiResponse {
-            message: "Client not found".into(),
+        .collect();
+
+    let completed = results
+        .iter()
+        .filter(|d| {
+            d.response
+                .job
+                .as_ref()
+                .is_some_and(|job| matches!(job.status, JobStatus::Completed { ok: true, .. }))
         })
-    }
+        .count();
+
+    let scope = req.group.as_deref().unwrap_or("all clients");
+    let message = format!("{} of {} completed", completed, results.len());
+
+    println!(
+        "[HTTP]: Broadcast '{}' to {}: {}",
+        req.message, scope, message
+    );
+
+    state.audit.record(
+        &caller,
+        "broadcast",
+        targets,
+        completed == results.len(),
+        format!("{} to {}: {}", req.message, scope, message),
+    );
+
+    Ok(Json(BroadcastResponse { message, results }))
 }
 
-async fn stop_server(State(state): State<AppState>) -> Json<ApiResponse> {
+async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Job>, ApiError> {
+    state
+        .jobs
+        .lock()
+        .await
+        .get(id)
+        .map(Json)
+        .ok_or(ApiError::not_found("Job not found"))
+}
+
+async fn stop_server(State(state): State<AppState>, caller: Caller) -> Json<ApiResponse> {
+    state.audit.record(&caller, "stop", Vec::new(), true, "");
     let _ = state.shutdown.send(());
 
     Json(ApiResponse {
         message: "Stopping Sentinel".into(),
     })
 }
+
+// Same as SIGHUP: re-reads the config file and applies what it can live.
+async fn reload_config(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<Json<ReloadReport>, ApiError> {
+    match state.reloader.reload().await {
+        Ok(report) => {
+            log_report("[CONFIG]:", &report);
+            state.audit.record(
+                &caller,
+                "reload",
+                Vec::new(),
+                true,
+                format!(
+                    "applied {:?}, restart required for {:?}",
+                    report.applied, report.restart_required
+                ),
+            );
+            Ok(Json(report))
+        }
+        Err(e) => {
+            println!("[CONFIG]: Reload rejected: {}", e);
+            state
+                .audit
+                .record(&caller, "reload", Vec::new(), false, e.to_string());
+
+            // a file that cannot be read is the server's problem; one that
+            // does not parse or validate is the caller's to fix
+            let unreadable = e.chain().any(|cause| cause.is::<std::io::Error>());
+            let status = if unreadable {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                StatusCode::UNPROCESSABLE_ENTITY
+            };
+
+            Err(ApiError::new(status, format!("Reload rejected: {:#}", e)))
+        }
+    }
+}
+
+async fn list_policies(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let store = state.policies.lock().await;
+
+    Json(serde_json::to_value(&*store).unwrap_or_default())
+}
+
+async fn list_groups(State(state): State<AppState>) -> Json<Vec<GroupView>> {
+    let groups = state.groups.lock().await;
+    let clients = state.clients.lock().await;
+
+    Json(
+        groups
+            .all()
+            .into_iter()
+            .map(|(name, rules, source)| {
+                let mut online: Vec<usize> = clients
+                    .iter()
+                    .filter(|(id, meta)| rules.matches(**id, &meta.addr))
+                    .map(|(id, _)| *id)
+                    .collect();
+                online.sort_unstable();
+
+                GroupView {
+                    name: name.to_string(),
+                    source,
+                    rules: rules.clone(),
+                    online,
+                }
+            })
+            .collect(),
+    )
+}
+
+// Creates or replaces an API group. Policies follow the new membership.
+async fn put_group(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(name): Path<String>,
+    Json(rules): Json<GroupRules>,
+) -> Result<Json<GroupUpdate>, ApiError> {
+    let policies = state.policies.lock().await;
+    let mut groups = state.groups.lock().await;
+
+    if let Err(e) = groups.set(&name, rules.clone()) {
+        state.audit.record(
+            &caller,
+            "group.set",
+            Vec::new(),
+            false,
+            format!("{}: {}", name, e),
+        );
+
+        return Err(if groups.is_configured(&name) {
+            ApiError::conflict(format!("Group rejected: {:#}", e))
+        } else if e.chain().any(|c| c.is::<std::io::Error>()) {
+            ApiError::internal(format!("Group not saved: {:#}", e))
+        } else {
+            ApiError::bad_request(format!("Group rejected: {:#}", e))
+        });
+    }
+
+    let pushed = policy::push(&state.clients, &policies, &groups).await;
+
+    let mut online: Vec<usize> = state
+        .clients
+        .lock()
+        .await
+        .iter()
+        .filter(|(id, meta)| rules.matches(**id, &meta.addr))
+        .map(|(id, _)| *id)
+        .collect();
+    online.sort_unstable();
+
+    println!("[HTTP]: Group {} saved, online {:?}", name, online);
+
+    state
+        .audit
+        .record(&caller, "group.set", online.clone(), true, name.clone());
+
+    Ok(Json(GroupUpdate {
+        message: format!("Group {} saved", name),
+        online,
+        pushed,
+    }))
+}
+
+async fn delete_group(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(name): Path<String>,
+) -> Result<Json<GroupUpdate>, ApiError> {
+    let policies = state.policies.lock().await;
+    let mut groups = state.groups.lock().await;
+
+    let failure = match groups.remove(&name) {
+        Ok(true) => None,
+        Ok(false) => Some(ApiError::not_found("Group not found")),
+        Err(e) if groups.is_configured(&name) => {
+            Some(ApiError::conflict(format!("Group not removed: {}", e)))
+        }
+        Err(e) => Some(ApiError::internal(format!("Group not removed: {}", e))),
+    };
+
+    if let Some(error) = failure {
+        state.audit.record(
+            &caller,
+            "group.remove",
+            Vec::new(),
+            false,
+            format!("{}: {}", name, error.message),
+        );
+
+        return Err(error);
+    }
+
+    let pushed = policy::push(&state.clients, &policies, &groups).await;
+    println!("[HTTP]: Group {} removed", name);
+
+    state
+        .audit
+        .record(&caller, "group.remove", Vec::new(), true, name.clone());
+
+    Ok(Json(GroupUpdate {
+        message: format!("Group {} removed", name),
+        online: Vec::new(),
+        pushed,
+    }))
+}
+
+async fn put_default(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(req): Json<PolicyRequest>,
+) -> Result<Json<PolicyUpdate>, ApiError> {
+    update_policy(&state, &caller, Scope::Default, req).await
+}
+
+async fn put_policy(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((kind, name)): Path<(String, String)>,
+    Json(req): Json<PolicyRequest>,
+) -> Result<Json<PolicyUpdate>, ApiError> {
+    let scope = Scope::parse(&kind, &name).map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    update_policy(&state, &caller, scope, req).await
+}
+
+async fn delete_default(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<Json<PolicyUpdate>, ApiError> {
+    remove_policy(&state, &caller, Scope::Default).await
+}
+
+async fn delete_policy(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((kind, name)): Path<(String, String)>,
+) -> Result<Json<PolicyUpdate>, ApiError> {
+    let scope = Scope::parse(&kind, &name).map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    remove_policy(&state, &caller, scope).await
+}
+
+// Stores the policy and sends it straight to every connected client it
+// now applies to.
+async fn update_policy(
+    state: &AppState,
+    caller: &Caller,
+    scope: Scope,
+    req: PolicyRequest,
+) -> Result<Json<PolicyUpdate>, ApiError> {
+    let mut store = state.policies.lock().await;
+    let name = scope.to_string();
+
+    let revision = match store.set(scope, req.policy, req.members) {
+        Ok(revision) => revision,
+        Err(e) => {
+            state.audit.record(
+                caller,
+                "policy.set",
+                Vec::new(),
+                false,
+                format!("{}: {}", name, e),
+            );
+
+            // a policy that could not be written is our failure, not the
+            // caller's
+            return Err(if e.chain().any(|c| c.is::<std::io::Error>()) {
+                ApiError::internal(format!("Policy not saved: {:#}", e))
+            } else {
+                ApiError::bad_request(format!("Policy rejected: {}", e))
+            });
+        }
+    };
+
+    let groups = state.groups.lock().await;
+    let pushed = policy::push(&state.clients, &store, &groups).await;
+    println!("[HTTP]: Policy {} saved, sent to {:?}", revision, pushed);
+
+    state.audit.record(
+        caller,
+        "policy.set",
+        pushed.clone(),
+        true,
+        format!("{} revision {}", name, revision),
+    );
+
+    Ok(Json(PolicyUpdate {
+        message: format!("Policy {} saved", revision),
+        revision: Some(revision),
+        pushed,
+    }))
+}
+
+// Clients that ran the removed policy fall back to the next one that
+// applies to them.
+async fn remove_policy(
+    state: &AppState,
+    caller: &Caller,
+    scope: Scope,
+) -> Result<Json<PolicyUpdate>, ApiError> {
+    let mut store = state.policies.lock().await;
+    let name = scope.to_string();
+
+    let failure = match store.remove(scope) {
+        Ok(true) => None,
+        Ok(false) => Some(ApiError::not_found("Policy not found")),
+        Err(e) => Some(ApiError::internal(format!("Policy not removed: {}", e))),
+    };
+
+    if let Some(error) = failure {
+        state.audit.record(
+            caller,
+            "policy.remove",
+            Vec::new(),
+            false,
+            format!("{}: {}", name, error.message),
+        );
+
+        return Err(error);
+    }
+
+    let groups = state.groups.lock().await;
+    let pushed = policy::push(&state.clients, &store, &groups).await;
+    println!("[HTTP]: Policy removed, sent to {:?}", pushed);
+
+    state
+        .audit
+        .record(caller, "policy.remove", pushed.clone(), true, name);
+
+    Ok(Json(PolicyUpdate {
+        message: "Policy removed".into(),
+        revision: None,
+        pushed,
+    }))
+}
+
+async fn query_audit(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    let audit = state.audit.clone();
+
+    tokio::task::spawn_blocking(move || audit.query(&query))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|entries| entries)
+        .map(Json)
+        .map_err(|e| ApiError::internal(format!("Could not read audit log: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(uri: &str, authorization: Option<&str>) -> Parts {
+        let mut req = axum::http::Request::builder().uri(uri);
+
+        if let Some(value) = authorization {
+            req = req.header(header::AUTHORIZATION, value);
+        }
+
+        req.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn header_token_works_everywhere() {
+        let parts = parts("/clients", Some("Bearer snt_abc"));
+        assert_eq!(bearer(&parts).as_deref(), Some("snt_abc"));
+    }
+
+    #[test]
+    fn query_token_only_on_streams() {
+        for route in ["/events", "/events/ws", "/kafka/ws"] {
+            let parts = parts(&format!("{}?token=snt_abc", route), None);
+            assert_eq!(bearer(&parts).as_deref(), Some("snt_abc"), "{}", route);
+        }
+
+        for route in ["/clients", "/send/27", "/audit", "/events/x"] {
+            let parts = parts(&format!("{}?token=snt_abc", route), None);
+            assert_eq!(bearer(&parts), None, "{}", route);
+        }
+    }
+}
@@ -1,2 +1,4 @@
 mod kafka_ws;
+mod live;
 pub mod main;
+mod unix;
@@ -0,0 +1,60 @@
+use nix::unistd::{getgrouplist, Gid, Group, Uid, User};
+use sentinel_protocol::local::PeerCred;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+
+use crate::auth::{Identity, Role};
+
+// root is always admin; anyone else needs an entry in api.socket_users,
+// keyed by user name, by uid ("1001") or by group ("@proctors"). A uid or
+// name entry wins over groups; of several groups the highest role counts.
+pub fn identity(peer: &PeerCred, users: &BTreeMap<String, Role>) -> Option<Identity> {
+    let uid = peer.uid?;
+
+    if uid == 0 {
+        return Some(Identity {
+            name: "root".into(),
+            role: Role::Admin,
+        });
+    }
+
+    let user = User::from_uid(Uid::from_raw(uid)).ok().flatten();
+    let name = user
+        .as_ref()
+        .map_or_else(|| uid.to_string(), |user| user.name.clone());
+
+    let own = users
+        .get(&uid.to_string())
+        .or_else(|| user.as_ref().and_then(|user| users.get(&user.name)));
+
+    let role = match own {
+        Some(role) => *role,
+        None => groups(peer, user.as_ref())
+            .iter()
+            .filter_map(|group| users.get(&format!("@{}", group)))
+            .max()
+            .copied()?,
+    };
+
+    Some(Identity { name, role })
+}
+
+// Names of the caller's primary group and, if they have an account, of
+// every group it lists them in.
+fn groups(peer: &PeerCred, user: Option<&User>) -> Vec<String> {
+    let mut gids: Vec<Gid> = peer.gid.map(Gid::from_raw).into_iter().collect();
+
+    if let Some(user) = user {
+        if let Ok(name) = CString::new(user.name.as_str()) {
+            gids.extend(getgrouplist(&name, user.gid).unwrap_or_default());
+        }
+    }
+
+    gids.sort_by_key(|gid| gid.as_raw());
+    gids.dedup();
+
+    gids.into_iter()
+        .filter_map(|gid| Group::from_gid(gid).ok().flatten())
+        .map(|group| group.name)
+        .collect()
+}
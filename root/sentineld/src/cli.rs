@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Result};
+
+use crate::auth::Role;
+use crate::config::{Config, DEFAULT_PATH};
+
+pub const USAGE: &str = "\
+Usage: sentineld [options]
+       sentineld token add NAME [--role ROLE] [-c PATH]
+       sentineld token list [-c PATH]
+       sentineld token remove NAME [-c PATH]
+
+Options:
+  -c, --config PATH   config file (default /etc/sentinel/sentineld.toml)
+      --no-daemon     stay in the foreground
+      --ip ADDR       address sentries connect to (server.ip)
+      --port PORT     client port (server.port)
+      --http ADDR     HTTP API address (server.http)
+      --check         validate the config and exit
+  -h, --help          print this help
+
+Token options:
+      --role ROLE     viewer (default), invigilator or admin
+";
+
+// Manages the HTTP API tokens in api.tokens, then exits.
+#[derive(Clone, Debug)]
+pub enum TokenCommand {
+    Add { name: String, role: Role },
+    List,
+    Remove { name: String },
+}
+
+fn parse_role(role: &str) -> Result<Role> {
+    match role {
+        "viewer" => Ok(Role::Viewer),
+        "invigilator" => Ok(Role::Invigilator),
+        "admin" => Ok(Role::Admin),
+        other => Err(anyhow!(
+            "--role: '{}' is not viewer, invigilator or admin",
+            other
+        )),
+    }
+}
+
+// Command line options. Anything set here wins over the config file.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    pub config: Option<String>,
+    pub no_daemon: bool,
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+    pub http: Option<String>,
+    pub check: bool,
+    pub help: bool,
+    pub token: Option<TokenCommand>,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter().peekable();
+
+        if args.peek().map(String::as_str) == Some("token") {
+            args.next();
+
+            let sub = args.next().unwrap_or_default();
+            let mut name = || {
+                args.next()
+                    .filter(|name| !name.starts_with('-'))
+                    .ok_or_else(|| anyhow!("token {} needs a NAME\n\n{}", sub, USAGE))
+            };
+
+            parsed.token = Some(match sub.as_str() {
+                "add" => TokenCommand::Add {
+                    name: name()?,
+                    role: Role::Viewer,
+                },
+                "list" => TokenCommand::List,
+                "remove" => TokenCommand::Remove { name: name()? },
+                other => return Err(anyhow!("unknown token command '{}'\n\n{}", other, USAGE)),
+            });
+        }
+
+        while let Some(arg) = args.next() {
+            let mut value =
+                |name: &str| args.next().ok_or_else(|| anyhow!("{} needs a value", name));
+
+            match arg.as_str() {
+                "-c" | "--config" => parsed.config = Some(value(&arg)?),
+                "--no-daemon" => parsed.no_daemon = true,
+                "--ip" => parsed.ip = Some(value(&arg)?),
+                "--port" => {
+                    let port = value(&arg)?;
+                    parsed.port = Some(
+                        port.parse()
+                            .map_err(|_| anyhow!("--port: '{}' is not a port", port))?,
+                    );
+                }
+                "--http" => parsed.http = Some(value(&arg)?),
+                "--check" => parsed.check = true,
+                "--role" => match &mut parsed.token {
+                    Some(TokenCommand::Add { role, .. }) => *role = parse_role(&value(&arg)?)?,
+                    _ => return Err(anyhow!("--role only applies to token add")),
+                },
+                "-h" | "--help" => parsed.help = true,
+                other => return Err(anyhow!("unknown option '{}'\n\n{}", other, USAGE)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    // Loads the config file and applies the overrides on top, validated.
+    pub fn config(&self) -> Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+
+        if self.no_daemon {
+            config.daemon.daemonize = false;
+        }
+
+        if let Some(ip) = &self.ip {
+            config.server.ip = ip.clone();
+        }
+
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+
+        if let Some(http) = &self.http {
+            config.server.http = http.clone();
+        }
+
+        let path = self.config.as_deref().unwrap_or(DEFAULT_PATH);
+        config.validate().map_err(|e| anyhow!("{}: {}", path, e))?;
+
+        Ok(config)
+    }
+}
@@ -1,17 +1,313 @@
-use std::{net::UdpSocket, sync::Arc};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap, collections::HashMap, fs, net::SocketAddr, net::UdpSocket, path::Path,
+    sync::Arc,
+};
 
 use tokio::sync::Mutex;
+use tracing_subscriber::EnvFilter;
 
-#[derive(Clone, Debug)]
+use crate::auth::Role;
+use crate::groups::{self, GroupRules};
+
+// Used when no -c is given. A missing file there just means defaults.
+pub const DEFAULT_PATH: &str = "/etc/sentinel/sentineld.toml";
+
+// Mirrors sentineld.toml section by section. Every key is optional, and
+// unknown keys are rejected so a typo does not silently fall back to the
+// default.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub server_ip: String,
+    pub server: ServerConfig,
+    pub api: ApiConfig,
+    pub daemon: DaemonConfig,
+    pub log: LogConfig,
+    pub kafka: KafkaConfig,
+    pub clients: ClientsConfig,
+    pub discovery: DiscoveryConfig,
+    pub policy: PolicyConfig,
+    pub registry: RegistryConfig,
+    pub audit: AuditConfig,
+    pub groups: GroupsConfig,
+    // named groups of clients, [group.<name>]
+    pub group: BTreeMap<String, GroupRules>,
+    pub tls: Option<TlsConfig>,
+    pub psk: Option<PskConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // address sentries connect to; "auto" picks the LAN facing one
+    pub ip: String,
+    pub port: u16,
+    // address of the HTTP API
+    pub http: String,
+}
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    // require a token from `sentineld token add` on every HTTP request
+    pub auth: bool,
+    // JSON file the tokens are kept in; refused unless owned by the
+    // daemon's user and writable by nobody else
+    pub tokens: String,
+    // web origins allowed to call the API from a browser, e.g.
+    // "http://localhost:5173"; none means same origin only
+    pub cors_origins: Vec<String>,
+
+    // the same API on a Unix socket, "" for none, in a directory only root
+    // can write to; the caller is looked up in socket_users by user name,
+    // uid or "@group" (root is always admin) before falling back to a token
+    pub socket: String,
+    pub socket_mode: u32,
+    pub socket_users: BTreeMap<String, Role>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    pub daemonize: bool,
+    pub pid: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // tracing filter, e.g. "info" or "info,rdkafka=warn"
+    pub level: String,
+    // where output goes once daemonized
     pub stdout: String,
     pub stderr: String,
-    pub pid: String,
 
-    pub daemonize: bool,
-    // pub verbose: bool,
+    // per client files (<id>.log), plus sentinel.log and unknown.log
+    pub dir: String,
+    // a file is rotated once it would grow past max_bytes or is older than
+    // max_age seconds (0 for no age limit); rotated files are gzipped and
+    // the newest `keep` per client are kept
+    pub max_bytes: u64,
+    pub max_age: u64,
+    pub keep: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    // bootstrap servers, defaults to port 9092 on server.ip
+    pub brokers: Option<String>,
+    // consumer group prefix for /kafka/ws subscribers
+    pub group_prefix: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientsConfig {
+    // seconds between pings, and of silence before a client is evicted
+    pub heartbeat_interval: u64,
+    pub heartbeat_timeout: u64,
+
+    // seconds a new connection gets to finish TLS and HELLO
+    pub handshake_timeout: u64,
+    // open client sockets allowed at once, in total and per source address
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+
+    // seconds POST /send waits for a client to report a command result
+    pub command_timeout: u64,
+
+    pub duplicate_policy: DuplicatePolicy,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    // answer UDP discovery probes from sentries
+    pub enabled: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    // JSON file the policies set through /policies are kept in
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    // SQLite database of known clients, their sessions and users
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // append-only JSON lines record of admin actions
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupsConfig {
+    // JSON file the groups set through /groups are kept in
+    pub path: String,
+}
+
+// What to do when a client connects with an id that is already online.
+// Takeover suits sentries reconnecting after a network drop, Reject keeps
+// a second machine from stealing an id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    Reject,
+    Takeover,
+}
+
+// Mutual TLS for the client link. Every sentry must present a certificate
+// signed by `ca`, and its common name becomes the client id.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub ca: String,
+    pub cert: String,
+    pub key: String,
+}
+
+// Pre-shared keys for the HELLO challenge. A client listed in `clients`
+// must answer with its own secret, everyone else with `lab_secret`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PskConfig {
+    pub lab_secret: Option<String>,
+    // TOML keys are strings, checked to be client ids in validate()
+    #[serde(rename = "clients")]
+    client_secrets: HashMap<String, String>,
+    #[serde(skip)]
+    pub clients: HashMap<usize, String>,
+}
+
+impl PskConfig {
+    pub fn secret_for(&self, id: usize) -> Option<&str> {
+        self.clients
+            .get(&id)
+            .or(self.lab_secret.as_ref())
+            .map(String::as_str)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ip: "auto".to_string(),
+            port: 1612,
+            http: "127.0.0.1:3737".to_string(),
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            auth: true,
+            tokens: "/var/lib/sentinel/tokens.json".to_string(),
+            cors_origins: Vec::new(),
+
+            socket: "/run/sentinel/sentinel.sock".to_string(),
+            socket_mode: 0o660,
+            socket_users: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            daemonize: true,
+            pid: "/tmp/sentinel.pid".to_string(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            stdout: "/tmp/sentinel.out".to_string(),
+            stderr: "/tmp/sentinel.err".to_string(),
+
+            dir: "/var/log/sentinel".to_string(),
+            max_bytes: 10 * 1024 * 1024,
+            max_age: 24 * 60 * 60,
+            keep: 7,
+        }
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            brokers: None,
+            group_prefix: "sentinel-ws".to_string(),
+        }
+    }
+}
+
+impl Default for ClientsConfig {
+    fn default() -> Self {
+        ClientsConfig {
+            heartbeat_interval: 10,
+            heartbeat_timeout: 30,
+
+            handshake_timeout: 10,
+            max_connections: 1024,
+            max_connections_per_ip: 4,
+
+            command_timeout: 10,
+
+            duplicate_policy: DuplicatePolicy::Takeover,
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig { enabled: true }
+    }
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        PolicyConfig {
+            path: "/var/lib/sentinel/policies.json".to_string(),
+        }
+    }
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            path: "/var/lib/sentinel/sentinel.db".to_string(),
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            path: "/var/log/sentinel/audit.log".to_string(),
+        }
+    }
+}
+
+impl Default for GroupsConfig {
+    fn default() -> Self {
+        GroupsConfig {
+            path: "/var/lib/sentinel/groups.json".to_string(),
+        }
+    }
 }
 
 fn get_local_ip() -> std::io::Result<String> {
@@ -27,23 +323,220 @@ fn get_local_ip() -> std::io::Result<String> {
 }
 
 impl Config {
-    pub fn new() -> Config {
-        let mut server_ip = "127.0.0.1".to_string();
-        match get_local_ip() {
-            Ok(ip) => server_ip = ip,
-            Err(e) => println!("Error: {}", e),
+    // Reads `path`, or the default location when None. Only an explicitly
+    // given file has to exist.
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (DEFAULT_PATH, false),
+        };
+
+        if !required && !Path::new(path).exists() {
+            return Ok(Config::default());
         }
-        Config {
-            server_ip: server_ip,
 
-            stdout: "/tmp/sentinel.out".to_string(),
-            stderr: "/tmp/sentinel.err".to_string(),
-            pid: "/tmp/sentinel.out".to_string(),
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+
+        toml::from_str(&text).map_err(|e| anyhow!("{}: {}", path, e))
+    }
+
+    // Fills in derived values and checks what serde cannot. Errors name the
+    // offending key the way it is written in the file.
+    pub fn validate(&mut self) -> Result<()> {
+        if self.server.ip.is_empty() || self.server.ip == "auto" {
+            self.server.ip = match get_local_ip() {
+                Ok(ip) => ip,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    "127.0.0.1".to_string()
+                }
+            };
+        }
+
+        let Ok(ip) = self.server.ip.parse::<std::net::IpAddr>() else {
+            return Err(anyhow!(
+                "server.ip: '{}' is not an IP address",
+                self.server.ip
+            ));
+        };
+
+        if self.server.port == 0 {
+            return Err(anyhow!("server.port: must not be 0"));
+        }
+
+        if self.server.http.parse::<SocketAddr>().is_err() {
+            return Err(anyhow!(
+                "server.http: '{}' is not an address like 127.0.0.1:3737",
+                self.server.http
+            ));
+        }
+
+        for origin in &self.api.cors_origins {
+            let valid = (origin.starts_with("http://") || origin.starts_with("https://"))
+                && !origin.ends_with('/')
+                && axum::http::HeaderValue::from_str(origin).is_ok();
+
+            if !valid {
+                return Err(anyhow!(
+                    "api.cors_origins: '{}' is not an origin like http://localhost:5173",
+                    origin
+                ));
+            }
+        }
+
+        if self.api.socket_mode > 0o777 {
+            return Err(anyhow!(
+                "api.socket_mode: {:#o} is not a permission mode like 0o660",
+                self.api.socket_mode
+            ));
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            return Err(anyhow!(
+                "log.level: '{}' is not a valid filter: {}",
+                self.log.level,
+                e
+            ));
+        }
 
-            daemonize: false,
-            // verbose: true,
+        if self.kafka.brokers.is_none() {
+            self.kafka.brokers = Some(std::net::SocketAddr::new(ip, 9092).to_string());
         }
+
+        let clients = &self.clients;
+
+        if clients.heartbeat_interval == 0 {
+            return Err(anyhow!("clients.heartbeat_interval: must be at least 1"));
+        }
+
+        if clients.heartbeat_timeout <= clients.heartbeat_interval {
+            return Err(anyhow!(
+                "clients.heartbeat_timeout: must be longer than clients.heartbeat_interval ({})",
+                clients.heartbeat_interval
+            ));
+        }
+
+        for (key, value) in [
+            ("clients.handshake_timeout", clients.handshake_timeout),
+            ("clients.command_timeout", clients.command_timeout),
+            ("clients.max_connections", clients.max_connections as u64),
+            (
+                "clients.max_connections_per_ip",
+                clients.max_connections_per_ip as u64,
+            ),
+        ] {
+            if value == 0 {
+                return Err(anyhow!("{}: must be at least 1", key));
+            }
+        }
+
+        if self.log.max_bytes == 0 {
+            return Err(anyhow!("log.max_bytes: must be at least 1"));
+        }
+
+        for (key, path) in [
+            ("log.dir", &self.log.dir),
+            ("policy.path", &self.policy.path),
+            ("registry.path", &self.registry.path),
+            ("audit.path", &self.audit.path),
+            ("groups.path", &self.groups.path),
+            ("api.tokens", &self.api.tokens),
+        ] {
+            if path.is_empty() {
+                return Err(anyhow!("{}: must not be empty", key));
+            }
+        }
+
+        for (name, rules) in &self.group {
+            if !groups::valid_name(name) {
+                return Err(anyhow!(
+                    "group.{}: names may only use letters, digits, '-', '_' and '.'",
+                    name
+                ));
+            }
+
+            rules
+                .validate()
+                .map_err(|e| anyhow!("group.{}.{:#}", name, e))?;
+        }
+
+        if let Some(tls) = &self.tls {
+            for (key, path) in [
+                ("tls.ca", &tls.ca),
+                ("tls.cert", &tls.cert),
+                ("tls.key", &tls.key),
+            ] {
+                if !Path::new(path).is_file() {
+                    return Err(anyhow!("{}: no such file '{}'", key, path));
+                }
+            }
+        }
+
+        if let Some(psk) = &mut self.psk {
+            psk.clients.clear();
+
+            for (id, secret) in &psk.client_secrets {
+                let parsed = id
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("psk.clients.{}: '{}' is not a client id", id, id))?;
+
+                if secret.is_empty() {
+                    return Err(anyhow!("psk.clients.{}: secret is empty", id));
+                }
+
+                psk.clients.insert(parsed, secret.clone());
+            }
+
+            if psk.lab_secret.as_deref() == Some("") {
+                return Err(anyhow!("psk.lab_secret: secret is empty"));
+            }
+
+            if psk.lab_secret.is_none() && psk.clients.is_empty() {
+                return Err(anyhow!(
+                    "psk: set lab_secret or at least one psk.clients entry"
+                ));
+            }
+        }
+
+        Ok(())
     }
 }
 
 pub type SharedConfig = Arc<Mutex<Config>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(ip: &str) -> Config {
+        let mut cfg = Config::default();
+        cfg.server.ip = ip.to_string();
+        cfg
+    }
+
+    #[test]
+    fn brokers_default_to_the_server_address() {
+        let mut cfg = config("10.0.0.5");
+        cfg.validate().unwrap();
+        assert_eq!(cfg.kafka.brokers.as_deref(), Some("10.0.0.5:9092"));
+
+        let mut cfg = config("fd00::5");
+        cfg.validate().unwrap();
+        assert_eq!(cfg.kafka.brokers.as_deref(), Some("[fd00::5]:9092"));
+    }
+
+    #[test]
+    fn rejects_a_server_ip_that_is_not_an_address() {
+        let err = config("sentinel.lan").validate().unwrap_err();
+        assert!(err.to_string().starts_with("server.ip:"));
+    }
+
+    #[test]
+    fn rejects_port_zero() {
+        let mut cfg = config("10.0.0.5");
+        cfg.server.port = 0;
+
+        let err = cfg.validate().unwrap_err();
+        assert!(err.to_string().starts_with("server.port:"));
+    }
+}
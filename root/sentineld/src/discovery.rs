@@ -0,0 +1,94 @@
+use anyhow::Result;
+use sentinel_protocol::discovery::{Discovery, DISCOVERY_GROUP, DISCOVERY_PORT, MAX_DATAGRAM};
+use sentinel_protocol::{auth, tls};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+
+use crate::config::SharedConfig;
+
+// Answers discovery probes from sentries with where to reach the TCP server.
+// With TLS the announcement carries the certificate's fingerprint, which
+// the sentry checks when it connects and pins. With a pre-shared key it
+// also proves the server holds the probing client's key; without either
+// anyone on the LAN could answer in its place.
+pub async fn run_discovery(
+    config: SharedConfig,
+    mut shutdown: broadcast::Receiver<()>,
+) -> Result<()> {
+    let (server_ip, port, tls_config, psk) = {
+        let cfg = config.lock().await;
+        (
+            cfg.server.ip.clone(),
+            cfg.server.port,
+            cfg.tls.clone(),
+            cfg.psk.clone(),
+        )
+    };
+
+    let fingerprint = match &tls_config {
+        Some(t) => Some(tls::cert_fingerprint(&t.cert)?),
+        None => None,
+    };
+    // The announced ip is the parsed one so the sentry, bracketing IPv6 the
+    // way SocketAddr does, checks the proof over the very same string.
+    let ip: IpAddr = server_ip.parse()?;
+    let addr = SocketAddr::new(ip, port).to_string();
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).await?;
+
+    if let Err(e) = socket.join_multicast_v4(DISCOVERY_GROUP, Ipv4Addr::UNSPECIFIED) {
+        println!("[DISCOVERY]: Multicast unavailable, broadcast only: {}", e);
+    }
+
+    match &fingerprint {
+        Some(fingerprint) => println!(
+            "[DISCOVERY]: Answering probes on port {} ({})",
+            DISCOVERY_PORT, fingerprint
+        ),
+        None => println!("[DISCOVERY]: Answering probes on port {}", DISCOVERY_PORT),
+    }
+
+    let mut buf = [0u8; MAX_DATAGRAM];
+
+    loop {
+        tokio::select! {
+
+            _ = shutdown.recv() => break,
+
+            result = socket.recv_from(&mut buf) => {
+                let (len, peer) = match result {
+                    Ok(received) => received,
+                    Err(e) => {
+                        println!("[DISCOVERY]: Receive failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let Some(Discovery::Probe { nonce, client_id }) = Discovery::from_bytes(&buf[..len]) else {
+                    continue;
+                };
+
+                let proof = client_id.as_deref().and_then(|client_id| {
+                    let secret = psk.as_ref()?.secret_for(client_id.parse().ok()?)?;
+                    Some(auth::sign_announce(secret, &nonce, client_id, &addr))
+                });
+
+                let announce = Discovery::Announce {
+                    nonce,
+                    ip: ip.to_string(),
+                    port,
+                    fingerprint: fingerprint.clone(),
+                    tls: tls_config.is_some(),
+                    proof,
+                };
+
+                if let Err(e) = socket.send_to(&announce.to_bytes(), peer).await {
+                    println!("[DISCOVERY]: Reply to {} failed: {}", peer, e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
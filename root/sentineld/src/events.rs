@@ -0,0 +1,180 @@
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::DuplicatePolicy;
+
+// Lifecycle events published by the TCP server. Anything interested in
+// clients coming and going subscribes to the bus instead of polling.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        id: usize,
+        session: u64,
+        addr: String,
+        version: String,
+    },
+    Disconnected {
+        id: usize,
+        session: u64,
+        reason: DisconnectReason,
+    },
+    // the client reported a different logged in user
+    UserChanged {
+        id: usize,
+        name: String,
+        regno: String,
+    },
+    // the client answered a command sent through /send or /broadcast
+    CommandResult {
+        id: usize,
+        job: u64,
+        command: String,
+        ok: bool,
+        output: String,
+    },
+    // someone tried to stop the client's sentry, as it reported
+    StopAttempt {
+        id: usize,
+        via: String,
+        allowed: bool,
+        uid: Option<u32>,
+        pid: Option<i32>,
+    },
+    // a second connection claimed an id that is already online
+    DuplicateId {
+        id: usize,
+        addr: String,
+        existing_addr: String,
+        policy: DuplicatePolicy,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    Closed,
+    Error,
+    Timeout,
+    Replaced,
+    // DELETE /clients/{id}
+    Kicked,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::Closed => "closed",
+            DisconnectReason::Error => "error",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::Replaced => "replaced",
+            DisconnectReason::Kicked => "kicked",
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            DisconnectReason::Closed => "connection closed",
+            DisconnectReason::Error => "connection error",
+            DisconnectReason::Timeout => "heartbeat timeout",
+            DisconnectReason::Replaced => "session taken over by a new connection",
+            DisconnectReason::Kicked => "disconnected by the server",
+        }
+    }
+}
+
+impl Event {
+    pub fn client(&self) -> usize {
+        match self {
+            Event::Connected { id, .. }
+            | Event::Disconnected { id, .. }
+            | Event::UserChanged { id, .. }
+            | Event::CommandResult { id, .. }
+            | Event::StopAttempt { id, .. }
+            | Event::DuplicateId { id, .. } => *id,
+        }
+    }
+}
+
+// Subscribers to the bus that fall behind miss events, which logs and live
+// streams can live with. The registry cannot, so it gets the events it
+// records over a channel of its own.
+#[derive(Clone)]
+pub struct EventBus {
+    bus: broadcast::Sender<Event>,
+    registry: mpsc::UnboundedSender<Event>,
+}
+
+impl EventBus {
+    pub fn send(&self, event: Event) {
+        if matches!(
+            event,
+            Event::Connected { .. } | Event::Disconnected { .. } | Event::UserChanged { .. }
+        ) {
+            let _ = self.registry.send(event.clone());
+        }
+
+        let _ = self.bus.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.bus.subscribe()
+    }
+}
+
+// The bus, and the receiving end of the registry's channel.
+pub fn new_bus() -> (EventBus, mpsc::UnboundedReceiver<Event>) {
+    let (registry, rx) = mpsc::unbounded_channel();
+
+    let bus = EventBus {
+        bus: broadcast::channel(256).0,
+        registry,
+    };
+
+    (bus, rx)
+}
+
+pub async fn log_events(mut rx: broadcast::Receiver<Event>) {
+    loop {
+        match rx.recv().await {
+            Ok(Event::Connected { id, addr, .. }) => {
+                println!("Client {} connected from {}", id, addr)
+            }
+            Ok(Event::Disconnected { id, reason, .. }) => {
+                println!("Client {} disconnected ({})", id, reason.describe())
+            }
+            Ok(Event::UserChanged { id, name, regno }) => {
+                println!("Client {} user is now {} {}", id, name, regno)
+            }
+            Ok(Event::CommandResult { id, job, ok, .. }) => {
+                println!(
+                    "Client {} finished job {} ({})",
+                    id,
+                    job,
+                    if ok { "ok" } else { "failed" }
+                )
+            }
+            Ok(Event::StopAttempt {
+                id, via, allowed, ..
+            }) => println!(
+                "Client {} {} stop attempt via {}",
+                id,
+                if allowed { "allowed" } else { "refused" },
+                via
+            ),
+            Ok(Event::DuplicateId {
+                id,
+                addr,
+                existing_addr,
+                policy,
+            }) => println!(
+                "Client {} from {} is already connected from {} ({:?})",
+                id, addr, existing_addr, policy
+            ),
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                println!("Event log skipped {} events", n)
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use anyhow::{anyhow, Context, Result};
+use sentinel_protocol::local;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Who belongs to a named group. A client is a member if any rule matches.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupRules {
+    pub ids: Vec<usize>,
+    // inclusive id ranges, e.g. "1-40"
+    pub ranges: Vec<String>,
+    // source address of the connection, e.g. "10.0.3.0/24"
+    pub subnets: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Config,
+    Api,
+}
+
+// Groups from the [group.<name>] tables of the config, which cannot be
+// changed through the API, and groups set through /groups, saved to `path`
+// after each change. A config group hides an API one of the same name.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GroupStore {
+    #[serde(skip)]
+    path: String,
+    #[serde(skip)]
+    configured: BTreeMap<String, GroupRules>,
+    groups: BTreeMap<String, GroupRules>,
+}
+
+pub type Groups = Arc<Mutex<GroupStore>>;
+
+fn parse_range(range: &str) -> Result<(usize, usize)> {
+    let parsed = range
+        .split_once('-')
+        .and_then(|(from, to)| Some((from.trim().parse().ok()?, to.trim().parse().ok()?)));
+
+    match parsed {
+        Some((from, to)) if from <= to => Ok((from, to)),
+        _ => Err(anyhow!("'{}' is not a range like 1-40", range)),
+    }
+}
+
+fn parse_subnet(subnet: &str) -> Result<(IpAddr, u32)> {
+    let parsed = subnet.split_once('/').and_then(|(ip, bits)| {
+        let ip: IpAddr = ip.parse().ok()?;
+        let bits: u32 = bits.parse().ok()?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        (bits <= max).then_some((ip, bits))
+    });
+
+    parsed.ok_or_else(|| anyhow!("'{}' is not a subnet like 10.0.3.0/24", subnet))
+}
+
+fn in_subnet(addr: IpAddr, (net, bits): (IpAddr, u32)) -> bool {
+    match (addr, net) {
+        (IpAddr::V4(addr), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+impl GroupRules {
+    pub fn validate(&self) -> Result<()> {
+        for range in &self.ranges {
+            parse_range(range).context("ranges")?;
+        }
+
+        for subnet in &self.subnets {
+            parse_subnet(subnet).context("subnets")?;
+        }
+
+        Ok(())
+    }
+
+    // `addr` is the client's connection address as shown in /clients.
+    // Rules are validated before they are stored, so bad ones never match.
+    pub fn matches(&self, id: usize, addr: &str) -> bool {
+        if self.ids.contains(&id) {
+            return true;
+        }
+
+        let in_range = self
+            .ranges
+            .iter()
+            .any(|range| parse_range(range).is_ok_and(|(from, to)| (from..=to).contains(&id)));
+
+        if in_range {
+            return true;
+        }
+
+        let Ok(addr) = addr.parse::<SocketAddr>().map(|a| a.ip()) else {
+            return false;
+        };
+
+        self.subnets
+            .iter()
+            .filter_map(|subnet| parse_subnet(subnet).ok())
+            .any(|subnet| in_subnet(addr, subnet))
+    }
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl GroupStore {
+    // A missing file is an empty store. One anybody but root could have
+    // written is refused.
+    pub fn open(path: &str, configured: BTreeMap<String, GroupRules>) -> Result<GroupStore> {
+        let mut store = match local::read_trusted(path)? {
+            Some(text) => serde_json::from_str(&text).map_err(|e| anyhow!("{}: {}", path, e))?,
+            None => GroupStore::default(),
+        };
+
+        store.path = path.to_string();
+        store.configured = configured;
+        Ok(store)
+    }
+
+    // Replaces the file in one step, like the policy store.
+    fn save(&self) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+
+        if let Some(dir) = Path::new(&self.path).parent() {
+            local::secure_dir(dir, 0o700)?;
+        }
+
+        local::write_atomic(&self.path, text.as_bytes(), 0o644)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&GroupRules> {
+        self.configured.get(name).or_else(|| self.groups.get(name))
+    }
+
+    pub fn is_configured(&self, name: &str) -> bool {
+        self.configured.contains_key(name)
+    }
+
+    // Every group by name.
+    pub fn all(&self) -> Vec<(&str, &GroupRules, Source)> {
+        let mut all: BTreeMap<&str, (&GroupRules, Source)> = self
+            .groups
+            .iter()
+            .map(|(name, rules)| (name.as_str(), (rules, Source::Api)))
+            .collect();
+
+        for (name, rules) in &self.configured {
+            all.insert(name, (rules, Source::Config));
+        }
+
+        all.into_iter()
+            .map(|(name, (rules, source))| (name, rules, source))
+            .collect()
+    }
+
+    // Names of the groups the client belongs to, in name order.
+    pub fn names_for(&self, id: usize, addr: &str) -> Vec<String> {
+        self.all()
+            .into_iter()
+            .filter(|(_, rules, _)| rules.matches(id, addr))
+            .map(|(name, _, _)| name.to_string())
+            .collect()
+    }
+
+    pub fn set(&mut self, name: &str, rules: GroupRules) -> Result<()> {
+        if !valid_name(name) {
+            return Err(anyhow!(
+                "'{}' is not a group name (letters, digits, '-', '_' and '.')",
+                name
+            ));
+        }
+
+        if self.is_configured(name) {
+            return Err(anyhow!("group '{}' is defined in the config", name));
+        }
+
+        rules.validate()?;
+
+        // changed on a copy, so a failed write leaves the group as it was
+        let mut next = self.clone();
+        next.groups.insert(name.to_string(), rules);
+        next.save()?;
+
+        *self = next;
+        Ok(())
+    }
+
+    // Returns false if there was nothing to remove.
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        if self.is_configured(name) {
+            return Err(anyhow!("group '{}' is defined in the config", name));
+        }
+
+        let mut next = self.clone();
+        let removed = next.groups.remove(name).is_some();
+
+        if removed {
+            next.save()?;
+            *self = next;
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(ids: &[usize], ranges: &[&str], subnets: &[&str]) -> GroupRules {
+        GroupRules {
+            ids: ids.to_vec(),
+            ranges: ranges.iter().map(|r| r.to_string()).collect(),
+            subnets: subnets.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn matches_ids_and_inclusive_ranges() {
+        let lab = rules(&[99], &["1-40"], &[]);
+
+        assert!(lab.matches(99, "10.0.0.1:5000"));
+        assert!(lab.matches(1, "10.0.0.1:5000"));
+        assert!(lab.matches(40, "10.0.0.1:5000"));
+        assert!(!lab.matches(41, "10.0.0.1:5000"));
+    }
+
+    #[test]
+    fn matches_the_connection_address_against_subnets() {
+        let lab = rules(&[], &[], &["10.0.3.0/24", "fd00::/64"]);
+
+        assert!(lab.matches(1, "10.0.3.17:5000"));
+        assert!(!lab.matches(1, "10.0.4.17:5000"));
+        assert!(lab.matches(1, "[fd00::17]:5000"));
+        assert!(!lab.matches(1, "[fd01::17]:5000"));
+        assert!(!lab.matches(1, "not an address"));
+    }
+
+    #[test]
+    fn zero_bits_match_every_address_of_the_family() {
+        let any = rules(&[], &[], &["0.0.0.0/0"]);
+
+        assert!(any.matches(1, "192.0.2.7:5000"));
+        assert!(!any.matches(1, "[fd00::17]:5000"));
+    }
+
+    #[test]
+    fn parse_subnet_rejects_bad_prefixes() {
+        assert!(parse_subnet("10.0.3.0/24").is_ok());
+        assert!(parse_subnet("fd00::/128").is_ok());
+        assert!(parse_subnet("10.0.3.0/33").is_err());
+        assert!(parse_subnet("10.0.3.0").is_err());
+        assert!(parse_subnet("lab/24").is_err());
+    }
+
+    #[test]
+    fn a_failed_write_changes_nothing() {
+        let mut store = GroupStore::default();
+        store.groups.insert("lab".into(), rules(&[1], &[], &[]));
+
+        // a file where the directory should be
+        store.path = format!("{}/groups.json", file!());
+
+        assert!(store.set("lab", rules(&[2], &[], &[])).is_err());
+        assert!(store.remove("lab").is_err());
+        assert_eq!(store.get("lab"), Some(&rules(&[1], &[], &[])));
+    }
+
+    #[test]
+    fn validate_names_the_bad_rule() {
+        assert!(rules(&[], &["40-1"], &[]).validate().is_err());
+        assert!(rules(&[], &[], &["10.0.3.0/24"]).validate().is_ok());
+    }
+}
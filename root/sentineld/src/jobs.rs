@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Result};
+use sentinel_protocol::ActionResult;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::{oneshot, Mutex};
+
+use crate::unix_time;
+
+// Jobs kept at once. The oldest finished ones make room for new ones;
+// pending jobs are never dropped, so once they fill the table no new
+// command is taken until some finish.
+const MAX_JOBS: usize = 1000;
+
+// One command sent to one client through POST /send.
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub client: usize,
+    pub session: u64,
+    pub action: String,
+    pub created: u64,
+    pub finished: Option<u64>,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Completed { ok: bool, output: String },
+    Failed { reason: String },
+}
+
+#[derive(Default)]
+pub struct JobTable {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+    waiters: HashMap<u64, oneshot::Sender<Job>>,
+}
+
+pub type Jobs = Arc<Mutex<JobTable>>;
+
+impl JobTable {
+    pub fn create(&mut self, client: usize, session: u64, action: String) -> Result<u64> {
+        while self.jobs.len() >= MAX_JOBS {
+            let finished = self
+                .jobs
+                .values()
+                .find(|job| !matches!(job.status, JobStatus::Pending))
+                .map(|job| job.id);
+
+            let Some(finished) = finished else {
+                return Err(anyhow!("{} commands are still pending", MAX_JOBS));
+            };
+
+            self.jobs.remove(&finished);
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+
+        self.jobs.insert(
+            id,
+            Job {
+                id,
+                client,
+                session,
+                action,
+                created: unix_time(),
+                finished: None,
+                status: JobStatus::Pending,
+            },
+        );
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.jobs.get(&id).cloned()
+    }
+
+    // Resolves with the job once it is no longer pending.
+    pub fn wait(&mut self, id: u64) -> oneshot::Receiver<Job> {
+        let (tx, rx) = oneshot::channel();
+
+        match self.jobs.get(&id) {
+            Some(job) if !matches!(job.status, JobStatus::Pending) => {
+                let _ = tx.send(job.clone());
+            }
+            _ => {
+                self.waiters.insert(id, tx);
+            }
+        }
+
+        rx
+    }
+
+    pub fn cancel_wait(&mut self, id: u64) {
+        self.waiters.remove(&id);
+    }
+
+    pub fn complete(&mut self, session: u64, result: ActionResult) {
+        self.finish(
+            session,
+            result.id,
+            JobStatus::Completed {
+                ok: result.ok,
+                output: result.output,
+            },
+        );
+    }
+
+    pub fn fail(&mut self, session: u64, id: u64, reason: &str) {
+        self.finish(
+            session,
+            id,
+            JobStatus::Failed {
+                reason: reason.to_string(),
+            },
+        );
+    }
+
+    // Called when a session goes away with commands still in flight.
+    pub fn fail_session(&mut self, session: u64, reason: &str) {
+        let pending: Vec<u64> = self
+            .jobs
+            .values()
+            .filter(|j| j.session == session && matches!(j.status, JobStatus::Pending))
+            .map(|j| j.id)
+            .collect();
+
+        for id in pending {
+            self.fail(session, id, reason);
+        }
+    }
+
+    fn finish(&mut self, session: u64, id: u64, status: JobStatus) {
+        // a session may only answer for its own commands
+        let Some(job) = self.jobs.get_mut(&id).filter(|j| j.session == session) else {
+            return;
+        };
+
+        job.status = status;
+        job.finished = Some(unix_time());
+
+        if let Some(waiter) = self.waiters.remove(&id) {
+            let _ = waiter.send(job.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_table_drops_oldest_finished_job() {
+        let mut jobs = JobTable::default();
+        let first = jobs.create(1, 1, "a".into()).unwrap();
+        let second = jobs.create(1, 1, "b".into()).unwrap();
+        jobs.fail(1, second, "done");
+
+        for _ in 2..MAX_JOBS {
+            jobs.create(1, 1, "c".into()).unwrap();
+        }
+
+        jobs.create(1, 1, "d".into()).unwrap();
+
+        assert!(jobs.get(first).is_some());
+        assert!(jobs.get(second).is_none());
+    }
+
+    #[test]
+    fn full_table_of_pending_jobs_refuses_more() {
+        let mut jobs = JobTable::default();
+        let first = jobs.create(1, 1, "a".into()).unwrap();
+        let _waiter = jobs.wait(first);
+
+        for _ in 1..MAX_JOBS {
+            jobs.create(1, 1, "b".into()).unwrap();
+        }
+
+        assert!(jobs.create(1, 1, "c".into()).is_err());
+        assert!(jobs.get(first).is_some());
+        assert!(jobs.waiters.contains_key(&first));
+    }
+}
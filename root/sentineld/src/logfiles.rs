@@ -0,0 +1,301 @@
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression};
+use sentinel_protocol::local;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::LogConfig;
+use crate::events::Event;
+use crate::unix_time;
+
+// Lines waiting for the writer before new ones are dropped.
+const BACKLOG: usize = 4096;
+
+// File for everything about a connection that never got a client id.
+const UNKNOWN: &str = "unknown";
+// File with the lifecycle of every client in one place.
+const SERVER: &str = "sentinel";
+
+struct Line {
+    file: String,
+    text: String,
+}
+
+// Hands lines to a writer thread, so connection tasks never wait on the
+// disk. Cheap to clone.
+#[derive(Clone)]
+pub struct LogFiles {
+    tx: mpsc::Sender<Line>,
+    dir: PathBuf,
+}
+
+struct Rotation {
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+}
+
+// Opens a file in the log directory, refusing a link in place of it.
+fn open(options: &mut OpenOptions, path: &Path) -> io::Result<File> {
+    options.custom_flags(nix::libc::O_NOFOLLOW).open(path)
+}
+
+// What the writer knows about a file it has appended to.
+struct Current {
+    size: u64,
+    started: SystemTime,
+}
+
+impl LogFiles {
+    // The directory must be root's alone: GET /clients/{id}/log serves
+    // what is in it, and root appends to whatever it finds there.
+    pub fn start(config: &LogConfig) -> Result<LogFiles> {
+        let dir = PathBuf::from(&config.dir);
+        local::secure_dir(&dir, 0o700)?;
+
+        let rotation = Rotation {
+            max_bytes: config.max_bytes,
+            max_age: (config.max_age > 0).then(|| Duration::from_secs(config.max_age)),
+            keep: config.keep,
+        };
+
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        let writer_dir = dir.clone();
+        std::thread::spawn(move || write_lines(writer_dir, rotation, rx));
+
+        Ok(LogFiles { tx, dir })
+    }
+
+    fn write(&self, file: &str, text: String) {
+        let line = Line {
+            file: file.to_string(),
+            text: format!("[{}] {}", unix_time(), text),
+        };
+
+        // a full backlog means the disk cannot keep up; losing a log line
+        // beats stalling a client
+        let _ = self.tx.try_send(line);
+    }
+
+    pub fn client(&self, id: usize, text: impl Into<String>) {
+        self.write(&id.to_string(), text.into());
+    }
+
+    pub fn unknown(&self, text: impl Into<String>) {
+        self.write(UNKNOWN, text.into());
+    }
+
+    pub fn path(&self, id: usize) -> PathBuf {
+        self.dir.join(format!("{}.log", id))
+    }
+}
+
+fn write_lines(dir: PathBuf, rotation: Rotation, mut rx: mpsc::Receiver<Line>) {
+    let mut files: HashMap<String, Current> = HashMap::new();
+
+    while let Some(line) = rx.blocking_recv() {
+        let path = dir.join(format!("{}.log", line.file));
+
+        let current = files.entry(line.file.clone()).or_insert_with(|| {
+            let meta = fs::symlink_metadata(&path).ok();
+            Current {
+                size: meta.as_ref().map_or(0, |m| m.len()),
+                started: meta
+                    .and_then(|m| m.created().or_else(|_| m.modified()).ok())
+                    .unwrap_or_else(SystemTime::now),
+            }
+        });
+
+        let len = line.text.len() as u64 + 1;
+        let too_big = current.size > 0 && current.size + len > rotation.max_bytes;
+        let too_old = rotation
+            .max_age
+            .is_some_and(|age| current.started.elapsed().unwrap_or_default() > age);
+
+        if too_big || too_old {
+            if let Err(e) = rotate(&dir, &line.file, rotation.keep) {
+                println!("[LOGS]: Could not rotate {}: {}", path.display(), e);
+            }
+
+            current.size = 0;
+            current.started = SystemTime::now();
+        }
+
+        let written = open(OpenOptions::new().create(true).append(true), &path)
+            .and_then(|mut file| writeln!(file, "{}", line.text));
+
+        match written {
+            Ok(()) => current.size += len,
+            Err(e) => println!("[LOGS]: Could not write {}: {}", path.display(), e),
+        }
+    }
+}
+
+// Moves <name>.log aside and compresses it in the background.
+fn rotate(dir: &Path, name: &str, keep: usize) -> io::Result<()> {
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let rotated = dir.join(format!("{}.{}.log", name, stamp));
+
+    fs::rename(dir.join(format!("{}.log", name)), &rotated)?;
+
+    let dir = dir.to_path_buf();
+    let name = name.to_string();
+
+    std::thread::spawn(move || {
+        if let Err(e) = compress(&rotated) {
+            println!("[LOGS]: Could not compress {}: {}", rotated.display(), e);
+        }
+
+        if let Err(e) = prune(&dir, &name, keep) {
+            println!("[LOGS]: Could not prune {} logs: {}", name, e);
+        }
+    });
+
+    Ok(())
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+
+    let mut input = open(OpenOptions::new().read(true), path)?;
+    let output = open(
+        OpenOptions::new().write(true).create_new(true),
+        Path::new(&gz_path),
+    )?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+
+    fs::remove_file(path)
+}
+
+// Deletes all but the newest `keep` rotations of <name>.log.
+fn prune(dir: &Path, name: &str, keep: usize) -> io::Result<()> {
+    let prefix = format!("{}.", name);
+    let mut rotated: Vec<(u128, PathBuf)> = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(file) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+
+        let stamp = file
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".log.gz"))
+            .and_then(|stamp| stamp.parse().ok());
+
+        if let Some(stamp) = stamp {
+            rotated.push((stamp, path));
+        }
+    }
+
+    rotated.sort_unstable_by_key(|(stamp, _)| std::cmp::Reverse(*stamp));
+
+    for (_, path) in rotated.into_iter().skip(keep) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+// Last `lines` lines of the file at `path`, oldest first. Reads backwards
+// from the end, so a large log costs no more than the lines asked for.
+pub fn tail(path: &Path, lines: usize) -> io::Result<Vec<String>> {
+    const CHUNK: u64 = 8192;
+
+    let mut file = open(OpenOptions::new().read(true), path)?;
+    let mut pos = file.metadata()?.len();
+    let mut buf: Vec<u8> = Vec::new();
+
+    // one more newline than lines wanted, since the file ends with one
+    while pos > 0 && buf.iter().filter(|b| **b == b'\n').count() <= lines {
+        let step = CHUNK.min(pos);
+        pos -= step;
+
+        let mut chunk = vec![0; step as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+
+        chunk.extend_from_slice(&buf);
+        buf = chunk;
+    }
+
+    let text = String::from_utf8_lossy(&buf);
+    let all: Vec<&str> = text.lines().collect();
+
+    Ok(all[all.len().saturating_sub(lines)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect())
+}
+
+// Writes client lifecycle events from the bus to the client's own file and
+// to sentinel.log.
+pub async fn write_events(files: LogFiles, mut rx: broadcast::Receiver<Event>) {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                files.write(SERVER, format!("Missed {} events", n));
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        let (id, text) = match event {
+            Event::Connected {
+                id, addr, version, ..
+            } => (id, format!("Connected from {} (version {})", addr, version)),
+            Event::Disconnected { id, reason, .. } => {
+                (id, format!("Disconnected ({})", reason.describe()))
+            }
+            Event::UserChanged { id, name, regno } => {
+                (id, format!("User is now {} {}", name, regno))
+            }
+            // the result itself is already in the client's log
+            Event::CommandResult { .. } => continue,
+            Event::StopAttempt {
+                id,
+                via,
+                allowed,
+                uid,
+                pid,
+            } => (
+                id,
+                format!(
+                    "Stop attempt via {} {} (uid {:?}, pid {:?})",
+                    via,
+                    if allowed { "allowed" } else { "refused" },
+                    uid,
+                    pid
+                ),
+            ),
+            Event::DuplicateId {
+                id,
+                addr,
+                existing_addr,
+                policy,
+            } => (
+                id,
+                format!(
+                    "Second connection from {} while connected from {} ({:?})",
+                    addr, existing_addr, policy
+                ),
+            ),
+        };
+
+        files.write(SERVER, format!("Client {}: {}", id, text));
+        files.client(id, text);
+    }
+}
@@ -1,50 +1,119 @@
 use anyhow::Result;
 use daemonize::Daemonize;
+use sentinel_protocol::reload::watch_sighup;
+use sentinel_protocol::{Capability, Message};
 use std::collections::HashMap;
 use std::fs::File;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::runtime::Builder;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::bridge::main::start_http;
+use crate::audit::{Audit, SharedAudit};
+use crate::auth::{SharedTokens, TokenStore};
+use crate::bridge::main::{start_http, AppState};
+use crate::cli::{Args, USAGE};
 use crate::config::{Config, SharedConfig};
-use crate::tcp::run_tcp_server;
+use crate::discovery::run_discovery;
+use crate::events::{log_events, new_bus, DisconnectReason};
+use crate::groups::{GroupStore, Groups};
+use crate::jobs::Jobs;
+use crate::logfiles::{write_events, LogFiles};
+use crate::policy::{Policies, PolicyStatus, PolicyStore};
+use crate::registry::{blocking, record_events, Registry, SharedRegistry};
+use crate::reload::Reloader;
+use crate::tcp::{run_tcp_server, Shared};
 
 struct ClientMeta {
-    tx: mpsc::Sender<String>,
+    tx: mpsc::Sender<Message>,
+    // tells the connection task to hang up
+    close: oneshot::Sender<DisconnectReason>,
+    session: u64,
     name: String,
     reg: String,
+    authenticated: bool,
+    version: String,
+    protocol: u32,
+    capabilities: Vec<Capability>,
+    addr: String,
+    // unix seconds of the last frame received
+    last_seen: u64,
+    rtt_ms: Option<u64>,
+    policy: PolicyStatus,
 }
 
+mod attendance;
+mod audit;
+mod auth;
 mod bridge;
+mod cli;
 mod config;
+mod discovery;
+mod events;
+mod groups;
+mod jobs;
+mod logfiles;
+mod policy;
+mod registry;
+mod reload;
 mod tcp;
 
 type Clients = Arc<Mutex<HashMap<usize, ClientMeta>>>;
 static CLIENT_COUNTER: AtomicUsize = AtomicUsize::new(1);
+static SESSION_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
 fn main() -> Result<()> {
-    let config = Config::new();
+    let args = Args::parse(std::env::args().skip(1))?;
+
+    if args.help {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+
+    let config = args.config()?;
 
-    tracing_subscriber::fmt::init();
+    if args.check {
+        println!("Config OK");
+        return Ok(());
+    }
 
-    if config.daemonize {
+    if let Some(command) = &args.token {
+        return auth::run_command(&config.api.tokens, command);
+    }
+
+    let (filter, log) =
+        tracing_subscriber::reload::Layer::new(EnvFilter::try_new(&config.log.level)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .init();
+
+    if config.daemon.daemonize {
         daemonize(&config)?;
     }
 
     let config: SharedConfig = Arc::new(Mutex::new(config));
+    let reloader = Arc::new(Reloader::new(args, config.clone(), log));
 
     let runtime = Builder::new_multi_thread().enable_all().build()?;
-    runtime.block_on(async_main(config.clone()))
+    runtime.block_on(async_main(config.clone(), reloader))
 }
 
 fn daemonize(config: &Config) -> Result<()> {
-    let stdout = File::create(&config.stdout)?;
-    let stderr = File::create(&config.stderr)?;
+    let stdout = File::create(&config.log.stdout)?;
+    let stderr = File::create(&config.log.stderr)?;
 
     let daemonize = Daemonize::new()
-        .pid_file(&config.pid)
+        .pid_file(&config.daemon.pid)
         .stdout(stdout)
         .stderr(stderr);
 
@@ -52,35 +121,121 @@ fn daemonize(config: &Config) -> Result<()> {
     Ok(())
 }
 
-async fn async_main(config: SharedConfig) -> Result<()> {
+async fn async_main(config: SharedConfig, reloader: Arc<Reloader>) -> Result<()> {
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let jobs: Jobs = Arc::new(Mutex::new(Default::default()));
     let (shutdown_tx, _) = broadcast::channel::<()>(1);
+    let (events, recorded) = new_bus();
+
+    let policies: Policies = {
+        let path = config.lock().await.policy.path.clone();
+        Arc::new(Mutex::new(PolicyStore::open(&path)?))
+    };
+
+    let groups: Groups = {
+        let cfg = config.lock().await;
+        Arc::new(Mutex::new(GroupStore::open(
+            &cfg.groups.path,
+            cfg.group.clone(),
+        )?))
+    };
+
+    let registry: SharedRegistry = {
+        let path = config.lock().await.registry.path.clone();
+        Arc::new(Registry::open(&path)?)
+    };
+
+    let logs = LogFiles::start(&config.lock().await.log)?;
+
+    let audit: SharedAudit = {
+        let path = config.lock().await.audit.path.clone();
+        Arc::new(Audit::open(&path)?)
+    };
+
+    let tokens: SharedTokens = {
+        let api = config.lock().await.api.clone();
+
+        if !api.auth {
+            println!("[HTTP]: Authentication disabled, every caller is admin");
+        } else if auth::list_tokens(&api.tokens)?.is_empty() {
+            println!(
+                "[HTTP]: No API tokens in {}, create one with: sentineld token add NAME --role admin",
+                api.tokens
+            );
+        }
+
+        Arc::new(TokenStore::new(&api.tokens))
+    };
+
+    tokio::spawn(log_events(events.subscribe()));
+    tokio::spawn(write_events(logs.clone(), events.subscribe()));
+    tokio::spawn(record_events(registry.clone(), recorded));
+
+    let sighup_reloader = reloader.clone();
+    tokio::spawn(async move {
+        if let Err(e) = watch_sighup("[CONFIG]:", || sighup_reloader.reload()).await {
+            eprintln!("SIGHUP handler error: {:?}", e);
+        }
+    });
 
-    let http_config = config.clone();
     let tcp_config = config.clone();
 
-    let tcp_clients = clients.clone();
-    let unix_clients = clients.clone();
+    let tcp_shared = Shared {
+        clients: clients.clone(),
+        jobs: jobs.clone(),
+        policies: policies.clone(),
+        groups: groups.clone(),
+        logs: logs.clone(),
+        events: events.clone(),
+    };
 
     let tcp_shutdown = shutdown_tx.subscribe();
     let unix_shutdown = shutdown_tx.subscribe();
 
     tokio::spawn(async move {
-        if let Err(e) = run_tcp_server(tcp_config, tcp_clients, tcp_shutdown).await {
+        if let Err(e) = run_tcp_server(tcp_config, tcp_shared, tcp_shutdown).await {
             eprintln!("TCP server error: {:?}", e);
         }
     });
 
-    let shutdown_tx_unix = shutdown_tx.clone();
+    if config.lock().await.discovery.enabled {
+        let discovery_config = config.clone();
+        let discovery_shutdown = shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            if let Err(e) = run_discovery(discovery_config, discovery_shutdown).await {
+                eprintln!("Discovery error: {:?}", e);
+            }
+        });
+    }
+
+    let state = AppState {
+        config: config.clone(),
+        clients,
+        jobs,
+        policies,
+        groups,
+        registry: registry.clone(),
+        logs,
+        audit,
+        tokens,
+        events,
+        reloader,
+        shutdown: shutdown_tx.clone(),
+    };
 
     tokio::spawn(async move {
-        if let Err(e) = start_http(http_config, unix_clients, shutdown_tx_unix, unix_shutdown).await
-        {
+        if let Err(e) = start_http(state, unix_shutdown).await {
             eprintln!("Unix server error: {:?}", e);
         }
     });
 
     shutdown_tx.subscribe().recv().await.ok();
     println!("Sentinel shutting down...");
+
+    if let Err(e) = blocking(&registry, |r| r.close_all("shutdown")).await {
+        println!("[REGISTRY]: Could not close sessions: {}", e);
+    }
+
     Ok(())
 }
@@ -0,0 +1,315 @@
+use anyhow::{anyhow, Result};
+use sentinel_protocol::{local, Capability, Message, Policy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::Mutex;
+
+use crate::groups::GroupStore;
+use crate::Clients;
+
+// A policy shared by a list of clients, plus every member of the named
+// group of the same name if there is one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupPolicy {
+    pub members: Vec<usize>,
+    pub policy: Policy,
+}
+
+// Which document a change through /policies is about.
+#[derive(Clone, Debug)]
+pub enum Scope {
+    Default,
+    Group(String),
+    Client(usize),
+}
+
+// Every policy set through the HTTP API, saved to `path` after each change.
+// A client runs its own policy if it has one, else that of the first group
+// (by name) it is in, else the default, else an empty one (revision 0).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PolicyStore {
+    #[serde(skip)]
+    path: String,
+    // last revision handed out; every stored document gets a new one, so
+    // a revision always names exactly one version of one document
+    revision: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<Policy>,
+    groups: BTreeMap<String, GroupPolicy>,
+    clients: BTreeMap<usize, Policy>,
+}
+
+pub type Policies = Arc<Mutex<PolicyStore>>;
+
+// Where a client stands with its policy, shown in GET /clients.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PolicyStatus {
+    // last revision the client acknowledged as applied
+    pub revision: Option<u64>,
+    // sent but not acknowledged yet
+    pub pending: Option<u64>,
+    // why the client refused the last policy sent
+    pub error: Option<String>,
+    // a new policy found the client's queue full; it goes out with the
+    // next heartbeat
+    pub retry: bool,
+}
+
+impl Scope {
+    pub fn parse(kind: &str, name: &str) -> Result<Scope> {
+        match kind {
+            "groups" if !name.is_empty() => Ok(Scope::Group(name.to_string())),
+            "clients" => name
+                .parse()
+                .map(Scope::Client)
+                .map_err(|_| anyhow!("'{}' is not a client id", name)),
+            _ => Err(anyhow!("unknown policy scope '{}/{}'", kind, name)),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Default => f.write_str("default"),
+            Scope::Group(name) => write!(f, "groups/{}", name),
+            Scope::Client(id) => write!(f, "clients/{}", id),
+        }
+    }
+}
+
+impl PolicyStore {
+    // A missing file is an empty store. One anybody but root could have
+    // written is refused.
+    pub fn open(path: &str) -> Result<PolicyStore> {
+        let mut store = match local::read_trusted(path)? {
+            Some(text) => serde_json::from_str(&text).map_err(|e| anyhow!("{}: {}", path, e))?,
+            None => PolicyStore::default(),
+        };
+
+        store.path = path.to_string();
+        Ok(store)
+    }
+
+    // Replaces the file in one step, so a crash never leaves half a store.
+    // The directory is created 0700 if need be, like the tokens file's.
+    fn save(&self) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+
+        if let Some(dir) = Path::new(&self.path).parent() {
+            local::secure_dir(dir, 0o700)?;
+        }
+
+        local::write_atomic(&self.path, text.as_bytes(), 0o644)
+    }
+
+    // `groups` are the names of the groups the client is in.
+    pub fn resolve(&self, id: usize, groups: &[String]) -> Policy {
+        self.clients
+            .get(&id)
+            .or_else(|| {
+                self.groups
+                    .iter()
+                    .find(|(name, group)| group.members.contains(&id) || groups.contains(name))
+                    .map(|(_, group)| &group.policy)
+            })
+            .or(self.default.as_ref())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Stores `policy` under a fresh revision and returns it. `members` only
+    // applies to groups. The change is made on a copy and only takes effect
+    // once it is on disk, so a failed write changes nothing.
+    pub fn set(&mut self, scope: Scope, mut policy: Policy, members: Vec<usize>) -> Result<u64> {
+        policy.validate()?;
+
+        if !members.is_empty() && !matches!(scope, Scope::Group(_)) {
+            return Err(anyhow!("members: only groups have members"));
+        }
+
+        let mut next = self.clone();
+        next.revision += 1;
+        policy.revision = next.revision;
+
+        match scope {
+            Scope::Default => next.default = Some(policy),
+            Scope::Group(name) => {
+                next.groups.insert(name, GroupPolicy { members, policy });
+            }
+            Scope::Client(id) => {
+                next.clients.insert(id, policy);
+            }
+        }
+
+        next.save()?;
+        *self = next;
+        Ok(self.revision)
+    }
+
+    // Returns false if there was nothing to remove. Like set, nothing
+    // changes unless the write succeeds.
+    pub fn remove(&mut self, scope: Scope) -> Result<bool> {
+        let mut next = self.clone();
+
+        let removed = match scope {
+            Scope::Default => next.default.take().is_some(),
+            Scope::Group(name) => next.groups.remove(&name).is_some(),
+            Scope::Client(id) => next.clients.remove(&id).is_some(),
+        };
+
+        if removed {
+            next.save()?;
+            *self = next;
+        }
+
+        Ok(removed)
+    }
+}
+
+// Sends every connected client whose policy changed its new one. Returns
+// the ids it was sent to.
+pub async fn push(clients: &Clients, store: &PolicyStore, groups: &GroupStore) -> Vec<usize> {
+    let mut guard = clients.lock().await;
+    let mut pushed = Vec::new();
+
+    for (id, meta) in guard.iter_mut() {
+        if !meta.capabilities.contains(&Capability::Policy) {
+            continue;
+        }
+
+        let policy = store.resolve(*id, &groups.names_for(*id, &meta.addr));
+        let current = meta.policy.pending.or(meta.policy.revision);
+
+        if current == Some(policy.revision) {
+            continue;
+        }
+
+        let revision = policy.revision;
+
+        match meta.tx.try_send(Message::Policy(policy)) {
+            Ok(()) => {
+                meta.policy.pending = Some(revision);
+                meta.policy.retry = false;
+                pushed.push(*id);
+            }
+            Err(TrySendError::Full(_)) => {
+                println!("Policy {} for client {} will be retried", revision, id);
+                meta.policy.retry = true;
+            }
+            Err(e) => println!("Policy {} for client {} not sent: {}", revision, id, e),
+        }
+    }
+
+    pushed.sort_unstable();
+    pushed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> PolicyStore {
+        let path = std::env::temp_dir().join(format!(
+            "sentineld-policy-{}/{}.json",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        PolicyStore::open(&path.to_string_lossy()).unwrap()
+    }
+
+    fn group(name: &str) -> Scope {
+        Scope::Group(name.to_string())
+    }
+
+    #[test]
+    fn a_failed_write_changes_nothing() {
+        let mut store = store("failed");
+        let kept = store
+            .set(Scope::Default, Policy::default(), vec![])
+            .unwrap();
+
+        // a file where the directory should be
+        store.path = format!("{}/policies.json", file!());
+
+        assert!(store
+            .set(Scope::Client(1), Policy::default(), vec![])
+            .is_err());
+        assert!(store.remove(Scope::Default).is_err());
+        assert_eq!(store.revision, kept);
+        assert_eq!(store.resolve(1, &[]).revision, kept);
+    }
+
+    #[test]
+    fn nothing_set_is_the_empty_policy() {
+        let store = store("empty");
+        assert_eq!(store.resolve(1, &[]).revision, 0);
+    }
+
+    #[test]
+    fn own_policy_beats_group_beats_default() {
+        let mut store = store("order");
+        let default = store
+            .set(Scope::Default, Policy::default(), vec![])
+            .unwrap();
+        let lab = store
+            .set(group("lab"), Policy::default(), vec![1, 2])
+            .unwrap();
+        let own = store
+            .set(Scope::Client(1), Policy::default(), vec![])
+            .unwrap();
+
+        assert_eq!(store.resolve(1, &[]).revision, own);
+        assert_eq!(store.resolve(2, &[]).revision, lab);
+        assert_eq!(store.resolve(3, &[]).revision, default);
+    }
+
+    #[test]
+    fn named_group_membership_counts() {
+        let mut store = store("named");
+        let lab = store
+            .set(group("Lab-3"), Policy::default(), vec![])
+            .unwrap();
+
+        assert_eq!(store.resolve(7, &["Lab-3".into()]).revision, lab);
+        assert_eq!(store.resolve(7, &["Lab-4".into()]).revision, 0);
+    }
+
+    #[test]
+    fn first_group_by_name_wins() {
+        let mut store = store("first");
+        let b = store.set(group("b"), Policy::default(), vec![5]).unwrap();
+        let a = store.set(group("a"), Policy::default(), vec![5]).unwrap();
+
+        assert!(b < a);
+        assert_eq!(store.resolve(5, &[]).revision, a);
+    }
+
+    #[test]
+    fn removing_falls_back() {
+        let mut store = store("remove");
+        let default = store
+            .set(Scope::Default, Policy::default(), vec![])
+            .unwrap();
+        store
+            .set(Scope::Client(4), Policy::default(), vec![])
+            .unwrap();
+
+        assert!(store.remove(Scope::Client(4)).unwrap());
+        assert!(!store.remove(Scope::Client(4)).unwrap());
+        assert_eq!(store.resolve(4, &[]).revision, default);
+    }
+
+    #[test]
+    fn only_groups_have_members() {
+        let mut store = store("members");
+        assert!(store
+            .set(Scope::Client(1), Policy::default(), vec![2])
+            .is_err());
+    }
+}
@@ -0,0 +1,512 @@
+use anyhow::Result;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use sentinel_protocol::local;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use crate::events::Event;
+use crate::unix_time;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS clients (
+    id         INTEGER PRIMARY KEY,
+    first_seen INTEGER NOT NULL,
+    last_seen  INTEGER NOT NULL,
+    last_addr  TEXT NOT NULL,
+    version    TEXT NOT NULL,
+    name       TEXT NOT NULL DEFAULT 'unknown',
+    regno      TEXT NOT NULL DEFAULT 'unknown'
+);
+
+-- `session` is the in-memory session id, only unique within one run,
+-- which is why sessions still open at startup are closed first
+CREATE TABLE IF NOT EXISTS sessions (
+    id      INTEGER PRIMARY KEY AUTOINCREMENT,
+    client  INTEGER NOT NULL,
+    session INTEGER NOT NULL,
+    addr    TEXT NOT NULL,
+    version TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    ended   INTEGER,
+    reason  TEXT
+);
+CREATE INDEX IF NOT EXISTS sessions_client ON sessions (client, started);
+
+CREATE TABLE IF NOT EXISTS users (
+    id     INTEGER PRIMARY KEY AUTOINCREMENT,
+    client INTEGER NOT NULL,
+    name   TEXT NOT NULL,
+    regno  TEXT NOT NULL,
+    at     INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS users_client ON users (client, at);
+
+-- a student logged in on a client, from INFO until logout, another user,
+-- or the client going away
+CREATE TABLE IF NOT EXISTS logins (
+    id      INTEGER PRIMARY KEY AUTOINCREMENT,
+    client  INTEGER NOT NULL,
+    name    TEXT NOT NULL,
+    regno   TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    ended   INTEGER,
+    reason  TEXT
+);
+CREATE INDEX IF NOT EXISTS logins_started ON logins (started);
+CREATE INDEX IF NOT EXISTS logins_regno ON logins (regno, started);
+";
+
+// Every client sentineld has ever seen, as of its last session.
+#[derive(Debug, Serialize)]
+pub struct KnownClient {
+    pub id: usize,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub last_addr: String,
+    pub version: String,
+    pub name: String,
+    pub regno: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionRecord {
+    pub started: u64,
+    // None while the session is still open
+    pub ended: Option<u64>,
+    pub addr: String,
+    pub version: String,
+    pub reason: Option<String>,
+}
+
+// A user the client reported as logged in, from `at` on.
+#[derive(Debug, Serialize)]
+pub struct UserRecord {
+    pub name: String,
+    pub regno: String,
+    pub at: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LoginRecord {
+    pub client: usize,
+    pub name: String,
+    pub regno: String,
+    pub started: u64,
+    // None while the student is still logged in
+    pub ended: Option<u64>,
+    // "logout", "switched", or why the client's session ended
+    pub reason: Option<String>,
+    // seconds, up to now for a login still open
+    pub duration: u64,
+}
+
+// Which logins to list. Every set field must match.
+#[derive(Clone, Debug, Default)]
+pub struct LoginFilter {
+    pub since: u64,
+    pub until: u64,
+    pub client: Option<usize>,
+    pub regno: Option<String>,
+    // all of them if None
+    pub limit: Option<usize>,
+}
+
+// Time window and size of a history query, in unix seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    pub since: u64,
+    pub until: u64,
+    pub limit: usize,
+}
+
+// SQLite record of clients, fed by record_events. Calls block, so async
+// code goes through blocking().
+pub struct Registry {
+    conn: Mutex<Connection>,
+}
+
+pub type SharedRegistry = Arc<Registry>;
+
+fn known_client(row: &Row) -> rusqlite::Result<KnownClient> {
+    Ok(KnownClient {
+        id: row.get::<_, i64>(0)? as usize,
+        first_seen: row.get::<_, i64>(1)? as u64,
+        last_seen: row.get::<_, i64>(2)? as u64,
+        last_addr: row.get(3)?,
+        version: row.get(4)?,
+        name: row.get(5)?,
+        regno: row.get(6)?,
+    })
+}
+
+const KNOWN_COLUMNS: &str = "id, first_seen, last_seen, last_addr, version, name, regno";
+
+impl Registry {
+    // The database sits in a directory only root can change and is never
+    // opened through a link, or anyone could have put their own in place.
+    pub fn open(path: &str) -> Result<Registry> {
+        if let Some(dir) = Path::new(path).parent() {
+            local::secure_dir(dir, 0o700)?;
+        }
+
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::default() | OpenFlags::SQLITE_OPEN_NOFOLLOW,
+        )?;
+        conn.execute_batch(SCHEMA)?;
+
+        // left open by a crash; the client's last_seen is the best guess
+        // for when they really ended
+        let closed = conn.execute(
+            "UPDATE sessions SET reason = 'restart',
+                ended = (SELECT last_seen FROM clients WHERE clients.id = sessions.client)
+             WHERE ended IS NULL",
+            [],
+        )?;
+
+        if closed > 0 {
+            println!("[REGISTRY]: Closed {} sessions left open", closed);
+        }
+
+        conn.execute(
+            "UPDATE logins SET reason = 'restart',
+                ended = MAX(started, (SELECT last_seen FROM clients WHERE clients.id = logins.client))
+             WHERE ended IS NULL",
+            [],
+        )?;
+
+        Ok(Registry {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // Writes a burst of events in one transaction. An event that fails is
+    // reported and skipped; the rest are still kept.
+    pub fn record(&self, events: Vec<Event>) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for event in events {
+            let result = match event {
+                Event::Connected {
+                    id,
+                    session,
+                    addr,
+                    version,
+                } => connected(&tx, id, session, &addr, &version),
+                Event::Disconnected {
+                    id,
+                    session,
+                    reason,
+                } => disconnected(&tx, id, session, reason.as_str()),
+                Event::UserChanged { id, name, regno } => user_changed(&tx, id, &name, &regno),
+                Event::CommandResult { .. }
+                | Event::StopAttempt { .. }
+                | Event::DuplicateId { .. } => Ok(()),
+            };
+
+            if let Err(e) = result {
+                println!("[REGISTRY]: Write failed: {}", e);
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Ends every open session, e.g. when the daemon stops.
+    pub fn close_all(&self, reason: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "UPDATE sessions SET ended = ?1, reason = ?2 WHERE ended IS NULL",
+            params![unix_time() as i64, reason],
+        )?;
+
+        conn.execute(
+            "UPDATE logins SET ended = ?1, reason = ?2 WHERE ended IS NULL",
+            params![unix_time() as i64, reason],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn known(&self) -> Result<Vec<KnownClient>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM clients ORDER BY id",
+            KNOWN_COLUMNS
+        ))?;
+
+        let clients = stmt
+            .query_map([], known_client)?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(clients)
+    }
+
+    pub fn client(&self, id: usize) -> Result<Option<KnownClient>> {
+        let conn = self.conn.lock().unwrap();
+
+        let client = conn
+            .query_row(
+                &format!("SELECT {} FROM clients WHERE id = ?1", KNOWN_COLUMNS),
+                params![id as i64],
+                known_client,
+            )
+            .optional()?;
+
+        Ok(client)
+    }
+
+    // Sessions overlapping the window, newest first.
+    pub fn sessions(&self, id: usize, window: Window) -> Result<Vec<SessionRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT started, ended, addr, version, reason FROM sessions
+             WHERE client = ?1 AND started <= ?3 AND (ended IS NULL OR ended >= ?2)
+             ORDER BY started DESC, id DESC LIMIT ?4",
+        )?;
+
+        let sessions = stmt
+            .query_map(
+                params![
+                    id as i64,
+                    window.since as i64,
+                    window.until as i64,
+                    window.limit as i64
+                ],
+                |row| {
+                    Ok(SessionRecord {
+                        started: row.get::<_, i64>(0)? as u64,
+                        ended: row.get::<_, Option<i64>>(1)?.map(|t| t as u64),
+                        addr: row.get(2)?,
+                        version: row.get(3)?,
+                        reason: row.get(4)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(sessions)
+    }
+
+    // User changes within the window, newest first.
+    pub fn users(&self, id: usize, window: Window) -> Result<Vec<UserRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT name, regno, at FROM users
+             WHERE client = ?1 AND at BETWEEN ?2 AND ?3
+             ORDER BY at DESC, id DESC LIMIT ?4",
+        )?;
+
+        let users = stmt
+            .query_map(
+                params![
+                    id as i64,
+                    window.since as i64,
+                    window.until as i64,
+                    window.limit as i64
+                ],
+                |row| {
+                    Ok(UserRecord {
+                        name: row.get(0)?,
+                        regno: row.get(1)?,
+                        at: row.get::<_, i64>(2)? as u64,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(users)
+    }
+
+    // Logins overlapping the window, oldest first.
+    pub fn logins(&self, filter: &LoginFilter) -> Result<Vec<LoginRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let now = unix_time();
+
+        let mut stmt = conn.prepare(
+            "SELECT client, name, regno, started, ended, reason FROM logins
+             WHERE started <= ?2 AND (ended IS NULL OR ended >= ?1)
+                AND (?3 IS NULL OR client = ?3)
+                AND (?4 IS NULL OR regno = ?4)
+             ORDER BY started, id LIMIT ?5",
+        )?;
+
+        let logins = stmt
+            .query_map(
+                params![
+                    filter.since as i64,
+                    filter.until as i64,
+                    filter.client.map(|id| id as i64),
+                    filter.regno,
+                    filter.limit.map_or(-1, |limit| limit as i64)
+                ],
+                |row| {
+                    let started = row.get::<_, i64>(3)? as u64;
+                    let ended = row.get::<_, Option<i64>>(4)?.map(|t| t as u64);
+
+                    Ok(LoginRecord {
+                        client: row.get::<_, i64>(0)? as usize,
+                        name: row.get(1)?,
+                        regno: row.get(2)?,
+                        started,
+                        ended,
+                        reason: row.get(5)?,
+                        duration: ended.unwrap_or(now).saturating_sub(started),
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(logins)
+    }
+}
+
+fn connected(conn: &Connection, id: usize, session: u64, addr: &str, version: &str) -> Result<()> {
+    let now = unix_time() as i64;
+
+    conn.execute(
+        "INSERT INTO clients (id, first_seen, last_seen, last_addr, version)
+         VALUES (?1, ?2, ?2, ?3, ?4)
+         ON CONFLICT (id) DO UPDATE SET
+            last_seen = excluded.last_seen,
+            last_addr = excluded.last_addr,
+            version = excluded.version",
+        params![id as i64, now, addr, version],
+    )?;
+
+    conn.execute(
+        "INSERT INTO sessions (client, session, addr, version, started)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![id as i64, session as i64, addr, version, now],
+    )?;
+
+    Ok(())
+}
+
+fn disconnected(conn: &Connection, id: usize, session: u64, reason: &str) -> Result<()> {
+    let now = unix_time() as i64;
+
+    conn.execute(
+        "UPDATE sessions SET ended = ?3, reason = ?4
+         WHERE client = ?1 AND session = ?2 AND ended IS NULL",
+        params![id as i64, session as i64, now, reason],
+    )?;
+
+    conn.execute(
+        "UPDATE clients SET last_seen = ?2 WHERE id = ?1",
+        params![id as i64, now],
+    )?;
+
+    end_login(conn, id, now as u64, reason)?;
+
+    Ok(())
+}
+
+// Only a user different from the last one recorded is kept, so a
+// reconnect re-sending the same INFO adds nothing.
+fn user_changed(conn: &Connection, id: usize, name: &str, regno: &str) -> Result<()> {
+    login(conn, id, name, regno)?;
+
+    let current: Option<(String, String)> = conn
+        .query_row(
+            "SELECT name, regno FROM clients WHERE id = ?1",
+            params![id as i64],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    if current.as_ref().map(|(n, r)| (n.as_str(), r.as_str())) == Some((name, regno)) {
+        return Ok(());
+    }
+
+    conn.execute(
+        "INSERT INTO users (client, name, regno, at) VALUES (?1, ?2, ?3, ?4)",
+        params![id as i64, name, regno, unix_time() as i64],
+    )?;
+
+    conn.execute(
+        "UPDATE clients SET name = ?2, regno = ?3 WHERE id = ?1",
+        params![id as i64, name, regno],
+    )?;
+
+    Ok(())
+}
+
+// The sentry reports "unknown" once the student logs out.
+fn logged_out(regno: &str) -> bool {
+    regno.is_empty() || regno == "unknown"
+}
+
+// Closes the client's open login, if any, and opens one for the new user
+// unless they are the one already logged in there.
+fn login(conn: &Connection, id: usize, name: &str, regno: &str) -> Result<()> {
+    let open: Option<String> = conn
+        .query_row(
+            "SELECT regno FROM logins WHERE client = ?1 AND ended IS NULL",
+            params![id as i64],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    if open.as_deref() == Some(regno) {
+        return Ok(());
+    }
+
+    let now = unix_time();
+    let reason = if logged_out(regno) {
+        "logout"
+    } else {
+        "switched"
+    };
+    end_login(conn, id, now, reason)?;
+
+    if !logged_out(regno) {
+        conn.execute(
+            "INSERT INTO logins (client, name, regno, started) VALUES (?1, ?2, ?3, ?4)",
+            params![id as i64, name, regno, now as i64],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn end_login(conn: &Connection, id: usize, at: u64, reason: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE logins SET ended = ?2, reason = ?3 WHERE client = ?1 AND ended IS NULL",
+        params![id as i64, at as i64, reason],
+    )?;
+
+    Ok(())
+}
+
+// Runs `f` on the blocking pool, keeping SQLite off the async threads.
+pub async fn blocking<T, F>(registry: &SharedRegistry, f: F) -> Result<T>
+where
+    F: FnOnce(&Registry) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let registry = registry.clone();
+    tokio::task::spawn_blocking(move || f(&registry)).await?
+}
+
+// Writes client lifecycle events into the registry. They come over their
+// own channel rather than the lossy bus, so none are missed; whatever has
+// piled up while the last write ran goes in as one transaction.
+pub async fn record_events(registry: SharedRegistry, mut rx: mpsc::UnboundedReceiver<Event>) {
+    while let Some(event) = rx.recv().await {
+        let mut batch = vec![event];
+
+        while let Ok(event) = rx.try_recv() {
+            batch.push(event);
+        }
+
+        if let Err(e) = blocking(&registry, move |r| r.record(batch)).await {
+            println!("[REGISTRY]: Write failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+use sentinel_protocol::reload::ReloadReport;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::cli::Args;
+use crate::config::{Config, SharedConfig};
+
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+// Keys picked up without a restart, matched as prefixes of dotted key
+// names. apply_live() below must copy exactly these.
+const LIVE: &[&str] = &["log.level", "kafka", "clients.command_timeout"];
+
+// Re-reads the config the daemon was started with, including command line
+// overrides, and applies it to the running one.
+pub struct Reloader {
+    args: Args,
+    config: SharedConfig,
+    log: LogHandle,
+}
+
+fn apply_live(running: &mut Config, new: &Config) {
+    running.log.level = new.log.level.clone();
+    running.kafka = new.kafka.clone();
+    running.clients.command_timeout = new.clients.command_timeout;
+}
+
+impl Reloader {
+    pub fn new(args: Args, config: SharedConfig, log: LogHandle) -> Reloader {
+        Reloader { args, config, log }
+    }
+
+    // An invalid file leaves the running config untouched.
+    pub async fn reload(&self) -> Result<ReloadReport> {
+        let new = self.args.config()?;
+        let mut running = self.config.lock().await;
+
+        let report = ReloadReport::compare(&*running, &new, LIVE)?;
+
+        if running.log.level != new.log.level {
+            self.log
+                .reload(EnvFilter::try_new(&new.log.level)?)
+                .map_err(|e| anyhow!("log.level: {}", e))?;
+        }
+
+        apply_live(&mut running, &new);
+
+        Ok(report)
+    }
+}
@@ -1,23 +1,128 @@
 use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use sentinel_protocol::{
+    auth, framed, negotiate_version, tls, BoxedStream, Capability, Connection, Message, Policy,
+    StopAttempt, STOP_ATTEMPT,
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::Ordering;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tokio::time::{interval_at, timeout, Instant};
+
+use crate::config::{DuplicatePolicy, PskConfig, SharedConfig};
+use crate::events::{DisconnectReason, Event, EventBus};
+use crate::groups::Groups;
+use crate::jobs::Jobs;
+use crate::logfiles::LogFiles;
+use crate::policy::{Policies, PolicyStatus};
+use crate::{unix_time, ClientMeta, Clients, CLIENT_COUNTER, SESSION_COUNTER};
+
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+// Everything a connection task needs besides its own socket.
+#[derive(Clone)]
+struct Server {
+    clients: Clients,
+    jobs: Jobs,
+    policies: Policies,
+    groups: Groups,
+    logs: LogFiles,
+    events: EventBus,
+    heartbeat: Heartbeat,
+    duplicates: DuplicatePolicy,
+    // highest replayed sequence number seen per client id
+    replays: Arc<Mutex<HashMap<usize, u64>>>,
+}
+
+// One registered connection. `session` is unique for the daemon's lifetime,
+// so a task only ever touches the client entry it created itself.
+struct Link {
+    id: usize,
+    session: u64,
+    rx: mpsc::Receiver<Message>,
+    close: oneshot::Receiver<DisconnectReason>,
+}
 
-use crate::config::SharedConfig;
-use crate::{ClientMeta, Clients, CLIENT_COUNTER};
+// State shared with the rest of the daemon.
+pub struct Shared {
+    pub clients: Clients,
+    pub jobs: Jobs,
+    pub policies: Policies,
+    pub groups: Groups,
+    pub logs: LogFiles,
+    pub events: EventBus,
+}
 
 pub async fn run_tcp_server(
     config: SharedConfig,
-    clients: Clients,
+    shared: Shared,
     mut shutdown: broadcast::Receiver<()>,
 ) -> Result<()> {
-    let server_ip = config.lock().await.server_ip.clone();
-    let addr = format!("{}:1612", server_ip);
+    let Shared {
+        clients,
+        jobs,
+        policies,
+        groups,
+        logs,
+        events,
+    } = shared;
+
+    let (server_ip, port, tls_config, psk, handshake_timeout, limits, server) = {
+        let cfg = config.lock().await;
+        (
+            cfg.server.ip.clone(),
+            cfg.server.port,
+            cfg.tls.clone(),
+            cfg.psk.clone(),
+            Duration::from_secs(cfg.clients.handshake_timeout),
+            Limits::new(
+                cfg.clients.max_connections,
+                cfg.clients.max_connections_per_ip,
+            ),
+            Server {
+                clients,
+                jobs,
+                policies,
+                groups,
+                logs,
+                events,
+                heartbeat: Heartbeat {
+                    interval: Duration::from_secs(cfg.clients.heartbeat_interval),
+                    timeout: Duration::from_secs(cfg.clients.heartbeat_timeout),
+                },
+                duplicates: cfg.clients.duplicate_policy,
+                replays: Arc::new(Mutex::new(HashMap::new())),
+            },
+        )
+    };
+
+    let acceptor = match tls_config {
+        Some(t) => Some(tls::acceptor(&t.ca, &t.cert, &t.key)?),
+        None => None,
+    };
+
+    let psk = psk.map(Arc::new);
+
+    let addr = SocketAddr::new(server_ip.parse()?, port);
     let listener = TcpListener::bind(addr).await?;
 
-    println!("[TCP]: Server Started at {}:1612", server_ip);
+    println!("[TCP]: Server Started at {}", addr);
+
+    if acceptor.is_some() {
+        println!("[TCP]: Mutual TLS enabled");
+    }
+
+    if psk.is_some() {
+        println!("[TCP]: Pre-shared key authentication enabled");
+    }
 
     loop {
         tokio::select! {
@@ -28,104 +133,691 @@ pub async fn run_tcp_server(
             }
 
             result = listener.accept() => {
-                let (mut stream, _) = result?;
+                let (stream, peer) = match result {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // usually out of file descriptors; back off instead
+                        // of spinning on the same error
+                        println!("[TCP]: Accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
 
-                let mut buffer = [0u8;1024];
-                let n = stream.read(&mut buffer).await?;
-                let msg = String::from_utf8_lossy(&buffer[..n]).to_string();
+                let Some(slot) = limits.acquire(peer.ip()) else {
+                    println!("[TCP]: Refused {}: too many connections", peer);
+                    continue;
+                };
 
-                let parts:Vec<&str> = msg.trim().split_whitespace().collect();
+                let incoming = Incoming {
+                    peer,
+                    slot,
+                    acceptor: acceptor.clone(),
+                    psk: psk.clone(),
+                };
 
-                let mut id = CLIENT_COUNTER.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(accept_client(stream, incoming, handshake_timeout, server.clone()));
+            }
+        }
+    }
 
-                if parts.len() == 3 && parts[0] == "HELLO" {
-                    id = parts[1].parse().unwrap_or(id);
-                    stream.write_all(b"AKN").await?;
-                }
+    Ok(())
+}
 
-                let (tx,rx) = mpsc::channel::<String>(32);
+// Caps how many sockets are open at once, overall and per source address,
+// counting connections that are still in the handshake.
+#[derive(Clone)]
+struct Limits {
+    inner: Arc<std::sync::Mutex<LimitState>>,
+}
 
-                clients.lock().await.insert(
-                    id,
-                    ClientMeta{
-                        tx,
-                        name:"unknown".into(),
-                        reg:"unknown".into()
-                    }
-                );
+struct LimitState {
+    max_total: usize,
+    max_per_ip: usize,
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// Held by a connection task for as long as its socket is open.
+struct Slot {
+    limits: Limits,
+    ip: IpAddr,
+}
 
-                println!("Client {} connected",id);
+impl Limits {
+    fn new(max_total: usize, max_per_ip: usize) -> Limits {
+        Limits {
+            inner: Arc::new(std::sync::Mutex::new(LimitState {
+                max_total,
+                max_per_ip,
+                total: 0,
+                per_ip: HashMap::new(),
+            })),
+        }
+    }
 
-                tokio::spawn(handle_tcp(id,stream,rx,clients.clone()));
+    fn acquire(&self, ip: IpAddr) -> Option<Slot> {
+        let mut state = self.inner.lock().unwrap();
+        let from_ip = state.per_ip.get(&ip).copied().unwrap_or(0);
+
+        if state.total >= state.max_total || from_ip >= state.max_per_ip {
+            return None;
+        }
+
+        state.total += 1;
+        state.per_ip.insert(ip, from_ip + 1);
+
+        Some(Slot {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.limits.inner.lock().unwrap();
+        state.total -= 1;
+
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
             }
         }
     }
+}
 
-    Ok(())
+// A freshly accepted socket and what it needs to get through the handshake.
+struct Incoming {
+    peer: SocketAddr,
+    slot: Slot,
+    acceptor: Option<tls::TlsAcceptor>,
+    psk: Option<Arc<PskConfig>>,
 }
 
-async fn handle_tcp(
-    id: usize,
+// Runs in its own task so a slow or silent peer only ever holds up itself.
+async fn accept_client(
     stream: TcpStream,
-    mut rx: mpsc::Receiver<String>,
-    clients: Clients,
+    incoming: Incoming,
+    handshake_timeout: Duration,
+    server: Server,
 ) {
-    let (read_half, mut write_half) = stream.into_split();
-    let mut reader = BufReader::new(read_half);
-    let mut line = String::new();
+    let Incoming {
+        peer,
+        slot,
+        acceptor,
+        psk,
+    } = incoming;
+
+    let greeting = async {
+        let (stream, identity): (BoxedStream, Option<String>) = match &acceptor {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    let identity = tls::peer_identity(stream.get_ref().1.peer_certificates());
+                    (Box::new(stream), identity)
+                }
+                Err(e) => {
+                    println!("TLS handshake with {} failed: {}", peer, e);
+                    server
+                        .logs
+                        .unknown(format!("TLS handshake with {} failed: {}", peer, e));
+                    return None;
+                }
+            },
+            None => (Box::new(stream), None),
+        };
+
+        let mut conn = framed(stream);
+        let session = handshake(
+            &mut conn,
+            peer,
+            acceptor.is_some(),
+            identity,
+            psk.as_deref(),
+            &server.logs,
+        )
+        .await?;
+
+        Some((conn, session))
+    };
+
+    let (mut conn, session) = match timeout(handshake_timeout, greeting).await {
+        Ok(Some(greeted)) => greeted,
+        Ok(None) => return,
+        Err(_) => {
+            println!("Handshake with {} timed out", peer);
+            server
+                .logs
+                .unknown(format!("Handshake with {} timed out", peer));
+            return;
+        }
+    };
+
+    let Some(link) = register(&server, &session, peer).await else {
+        reject(
+            &mut conn,
+            peer,
+            "client id is already connected",
+            &server.logs,
+        )
+        .await;
+        return;
+    };
+
+    if session.greeted {
+        let _ = conn
+            .send(Message::HelloAck {
+                client_id: session.id.to_string(),
+                protocol: session.protocol,
+                capabilities: session.capabilities.clone(),
+            })
+            .await;
+    }
 
-    loop {
+    // the full policy goes out with every handshake, so a sentry never
+    // keeps running one that changed while it was away
+    if session.capabilities.contains(&Capability::Policy) {
+        let groups = server
+            .groups
+            .lock()
+            .await
+            .names_for(session.id, &peer.to_string());
+        let policy = server.policies.lock().await.resolve(session.id, &groups);
+        let revision = policy.revision;
+
+        if conn.send(Message::Policy(policy)).await.is_ok() {
+            if let Some(meta) = owned(&mut *server.clients.lock().await, link.id, link.session) {
+                meta.policy.pending = Some(revision);
+            }
+        }
+    }
+
+    handle_tcp(link, conn, server).await;
+    drop(slot);
+}
+
+struct Session {
+    id: usize,
+    authenticated: bool,
+    // said HELLO and expects a HELLO_ACK once registered
+    greeted: bool,
+    version: String,
+    protocol: u32,
+    capabilities: Vec<Capability>,
+}
+
+impl Session {
+    // Clients that never said HELLO: first protocol version, no features.
+    fn legacy(id: usize) -> Session {
+        Session {
+            id,
+            authenticated: false,
+            greeted: false,
+            version: "unknown".into(),
+            protocol: 1,
+            capabilities: Vec::new(),
+        }
+    }
+}
+
+async fn reject(
+    conn: &mut Connection<BoxedStream>,
+    peer: SocketAddr,
+    reason: &str,
+    logs: &LogFiles,
+) {
+    println!("Rejected {}: {}", peer, reason);
+    logs.unknown(format!("Rejected {}: {}", peer, reason));
+
+    let _ = conn
+        .send(Message::Error {
+            message: reason.to_string(),
+        })
+        .await;
+}
+
+// Adds the client to the shared map, applying the duplicate id policy when
+// the id is already online. Returns None if the newcomer must be turned away.
+async fn register(server: &Server, session: &Session, peer: SocketAddr) -> Option<Link> {
+    let id = session.id;
+    let mut guard = server.clients.lock().await;
+
+    if let Some(existing) = guard.get(&id) {
+        server.events.send(Event::DuplicateId {
+            id,
+            addr: peer.to_string(),
+            existing_addr: existing.addr.clone(),
+            policy: server.duplicates,
+        });
+
+        if server.duplicates == DuplicatePolicy::Reject {
+            return None;
+        }
+    }
+
+    let session_id = SESSION_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let (tx, rx) = mpsc::channel::<Message>(32);
+    let (close_tx, close_rx) = oneshot::channel();
+
+    let previous = guard.insert(
+        id,
+        ClientMeta {
+            tx,
+            close: close_tx,
+            session: session_id,
+            name: "unknown".into(),
+            reg: "unknown".into(),
+            authenticated: session.authenticated,
+            version: session.version.clone(),
+            protocol: session.protocol,
+            capabilities: session.capabilities.clone(),
+            addr: peer.to_string(),
+            last_seen: unix_time(),
+            rtt_ms: None,
+            policy: PolicyStatus::default(),
+        },
+    );
+
+    // the old task sees this, says goodbye to its peer and exits without
+    // touching the new entry
+    if let Some(previous) = previous {
+        let _ = previous.close.send(DisconnectReason::Replaced);
+    }
+
+    server.events.send(Event::Connected {
+        id,
+        session: session_id,
+        addr: peer.to_string(),
+        version: session.version.clone(),
+    });
+
+    Some(Link {
+        id,
+        session: session_id,
+        rx,
+        close: close_rx,
+    })
+}
+
+// The client entry owned by `session`, if it has not been replaced.
+fn owned(
+    clients: &mut HashMap<usize, ClientMeta>,
+    id: usize,
+    session: u64,
+) -> Option<&mut ClientMeta> {
+    clients.get_mut(&id).filter(|meta| meta.session == session)
+}
+
+// Works out who is on the other end of `conn`. Returns None when the peer
+// went away or failed authentication, in which case it must be dropped.
+async fn handshake(
+    conn: &mut Connection<BoxedStream>,
+    peer: SocketAddr,
+    tls: bool,
+    identity: Option<String>,
+    psk: Option<&PskConfig>,
+    logs: &LogFiles,
+) -> Option<Session> {
+    let secured = tls || psk.is_some();
+    let mut id = CLIENT_COUNTER.fetch_add(1, Ordering::SeqCst);
+
+    let (claimed, version, protocols, capabilities) = match conn.next().await {
+        Some(Ok(Message::Hello {
+            client_id,
+            version,
+            protocols,
+            capabilities,
+        })) => (client_id, version, protocols, capabilities),
+        None => return None,
+
+        // Without any authentication configured, old style clients that
+        // skip HELLO are still let in under a counter id.
+        Some(Ok(other)) if !secured => {
+            println!("Expected HELLO, got {:?}", other);
+            return Some(Session::legacy(id));
+        }
+        Some(Err(e)) if !secured => {
+            println!("Handshake decode error: {}", e);
+            return Some(Session::legacy(id));
+        }
+        Some(_) => {
+            reject(conn, peer, "expected HELLO", logs).await;
+            return None;
+        }
+    };
+
+    id = claimed.parse().unwrap_or(id);
+    println!("HELLO from {} (version {})", id, version);
+
+    let Some(protocol) = negotiate_version(&protocols) else {
+        reject(conn, peer, "unsupported protocol version", logs).await;
+        return None;
+    };
+
+    let capabilities: Vec<Capability> = capabilities
+        .into_iter()
+        .filter(|c| *c != Capability::Unknown)
+        .collect();
+
+    // With mutual TLS the certificate decides who the client is,
+    // whatever it claimed in HELLO.
+    if tls {
+        match identity.as_deref().and_then(|cn| cn.parse::<usize>().ok()) {
+            Some(cert_id) => {
+                if cert_id != id {
+                    println!("Client {} claimed id {}, using certificate id", peer, id);
+                }
+                id = cert_id;
+            }
+            None => {
+                reject(conn, peer, "certificate has no client id", logs).await;
+                return None;
+            }
+        }
+    }
+
+    if let Some(psk) = psk {
+        let Some(secret) = psk.secret_for(id) else {
+            reject(conn, peer, "no pre-shared key for this client", logs).await;
+            return None;
+        };
+
+        let nonce = auth::nonce();
+
+        if conn
+            .send(Message::Challenge {
+                nonce: nonce.clone(),
+            })
+            .await
+            .is_err()
+        {
+            return None;
+        }
+
+        match conn.next().await {
+            Some(Ok(Message::Auth { mac }))
+                if auth::verify(secret, &nonce, &claimed, &version, &mac) => {}
+            Some(Ok(_)) => {
+                reject(conn, peer, "authentication failed", logs).await;
+                return None;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(Session {
+        id,
+        authenticated: secured,
+        greeted: true,
+        version,
+        protocol,
+        capabilities,
+    })
+}
+
+// The client's policy, resolved again, if the last push found its queue
+// full.
+async fn policy_retry(
+    clients: &Clients,
+    policies: &Policies,
+    groups: &Groups,
+    id: usize,
+    session: u64,
+) -> Option<Policy> {
+    let addr = {
+        let mut guard = clients.lock().await;
+        let meta = owned(&mut guard, id, session)?;
+
+        if !meta.policy.retry {
+            return None;
+        }
+
+        meta.addr.clone()
+    };
+
+    let names = groups.lock().await.names_for(id, &addr);
+    Some(policies.lock().await.resolve(id, &names))
+}
+
+async fn handle_tcp(link: Link, conn: Connection<BoxedStream>, server: Server) {
+    let Link {
+        id,
+        session,
+        mut rx,
+        mut close,
+    } = link;
+    let Server {
+        clients,
+        jobs,
+        policies,
+        groups,
+        events,
+        heartbeat,
+        replays,
+        logs,
+        ..
+    } = server;
+
+    let (mut sink, mut stream) = conn.split();
+
+    let mut ticker = interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+    let mut last_seen = Instant::now();
+    let mut seq: u64 = 0;
+    let mut pending: Option<(u64, Instant)> = None;
+
+    let reason = loop {
         tokio::select! {
 
-            result = reader.read_line(&mut line) => {
+            frame = stream.next() => {
 
-                match result {
+                match frame {
 
-                    Ok(0) => {
-                        println!("Client {} disconnected", id);
-                        clients.lock().await.remove(&id);
-                        return;
-                    }
+                    None => break DisconnectReason::Closed,
+
+                    Some(Ok(message)) => {
 
-                    Ok(_) => {
+                        last_seen = Instant::now();
 
-                        let message = line.trim().to_string();
-                        line.clear();
+                        if let Some(meta) = owned(&mut *clients.lock().await, id, session) {
+                            meta.last_seen = unix_time();
+                        }
+
+                        // queued while the client was offline, unwrap it
+                        // unless we already have it from an earlier attempt
+                        let message = match message {
+                            Message::Replayed { seq, queued_at, message } => {
+                                let _ = sink.send(Message::ReplayAck { seq }).await;
+
+                                let mut marks = replays.lock().await;
+                                let mark = marks.entry(id).or_insert(0);
+
+                                if seq <= *mark {
+                                    println!("Dropped duplicate replay {} from {}", seq, id);
+                                    logs.client(id, format!("Dropped duplicate replay {}", seq));
+                                    continue;
+                                }
 
-                        println!("From {}: {}", id, message);
+                                *mark = seq;
+                                println!("Replayed from {} (queued at {})", id, queued_at);
+                                logs.client(id, format!("Replayed (queued at {})", queued_at));
 
-                        if message.starts_with('{') {
+                                *message
+                            }
+                            message => message,
+                        };
+
+                        if !matches!(message, Message::Ping { .. } | Message::Pong { .. }) {
+                            println!("From {}: {:?}", id, message);
+                            logs.client(id, format!("{:?}", message));
+                        }
 
-                            if let Ok(v) = serde_json::from_str::<serde_json::Value>(&message) {
+                        match message {
+
+                            Message::Info(info) => {
 
                                 let mut guard = clients.lock().await;
 
-                                if let Some(meta) = guard.get_mut(&id) {
+                                if let Some(meta) = owned(&mut guard, id, session) {
 
-                                    if let Some(name) = v["name"].as_str() {
-                                        meta.name = name.to_string();
+                                    if meta.name != info.name || meta.reg != info.regno {
+                                        events.send(Event::UserChanged {
+                                            id,
+                                            name: info.name.clone(),
+                                            regno: info.regno.clone(),
+                                        });
                                     }
 
-                                    if let Some(reg) = v["regno"].as_str() {
-                                        meta.reg = reg.to_string();
-                                    }
+                                    meta.name = info.name;
+                                    meta.reg = info.regno;
 
                                     println!("Updated client {} -> {} {}", id, meta.name, meta.reg);
                                 }
                             }
+
+                            Message::Telemetry(telemetry) if telemetry.source == STOP_ATTEMPT => {
+
+                                match serde_json::from_value::<StopAttempt>(telemetry.data) {
+                                    Ok(attempt) => {
+                                        events.send(Event::StopAttempt {
+                                            id,
+                                            via: attempt.via,
+                                            allowed: attempt.allowed,
+                                            uid: attempt.uid,
+                                            pid: attempt.pid,
+                                        });
+                                    }
+                                    Err(e) => println!("Bad stop report from {}: {}", id, e),
+                                }
+                            }
+
+                            Message::ActionResult(result) => {
+                                events.send(Event::CommandResult {
+                                    id,
+                                    job: result.id,
+                                    command: result.command.clone(),
+                                    ok: result.ok,
+                                    output: result.output.clone(),
+                                });
+
+                                jobs.lock().await.complete(session, result);
+                            }
+
+                            Message::PolicyAck { revision, error } => {
+
+                                if let Some(meta) = owned(&mut *clients.lock().await, id, session) {
+
+                                    if meta.policy.pending == Some(revision) {
+                                        meta.policy.pending = None;
+                                    }
+
+                                    match error {
+                                        None => {
+                                            println!("Client {} applied policy {}", id, revision);
+                                            meta.policy.revision = Some(revision);
+                                            meta.policy.error = None;
+                                        }
+                                        Some(error) => {
+                                            println!("Client {} refused policy {}: {}", id, revision, error);
+                                            meta.policy.error = Some(error);
+                                        }
+                                    }
+                                }
+                            }
+
+                            Message::Ping { seq } => {
+                                let _ = sink.send(Message::Pong { seq }).await;
+                            }
+
+                            Message::Pong { seq } => {
+
+                                if let Some((sent_seq, sent_at)) = pending {
+
+                                    if sent_seq == seq {
+                                        pending = None;
+
+                                        if let Some(meta) = owned(&mut *clients.lock().await, id, session) {
+                                            meta.rtt_ms = Some(sent_at.elapsed().as_millis() as u64);
+                                        }
+                                    }
+                                }
+                            }
+
+                            _ => {}
                         }
                     }
 
-                    Err(_) => {
-                        clients.lock().await.remove(&id);
-                        return;
+                    Some(Err(e)) => {
+                        println!("Client {} read error: {}", id, e);
+                        break DisconnectReason::Error;
+                    }
+                }
+            }
+
+            reason = &mut close => {
+                // a dropped sender means our entry was removed from the map
+                let reason = reason.unwrap_or(DisconnectReason::Closed);
+                let _ = sink.send(Message::Error { message: reason.describe().into() }).await;
+                let _ = sink.close().await;
+                break reason;
+            }
+
+            _ = ticker.tick() => {
+
+                if last_seen.elapsed() > heartbeat.timeout {
+                    break DisconnectReason::Timeout;
+                }
+
+                seq += 1;
+                pending = Some((seq, Instant::now()));
+
+                if sink.send(Message::Ping { seq }).await.is_err() {
+                    break DisconnectReason::Error;
+                }
+
+                if let Some(policy) = policy_retry(&clients, &policies, &groups, id, session).await {
+                    let revision = policy.revision;
+
+                    if sink.send(Message::Policy(policy)).await.is_err() {
+                        break DisconnectReason::Error;
+                    }
+
+                    if let Some(meta) = owned(&mut *clients.lock().await, id, session) {
+                        meta.policy.pending = Some(revision);
+                        meta.policy.retry = false;
                     }
                 }
             }
 
             Some(msg) = rx.recv() => {
-                let _ = write_half.write_all(msg.as_bytes()).await;
+                let job = match &msg {
+                    Message::Action(action) => Some(action.id),
+                    _ => None,
+                };
+
+                if let Err(e) = sink.send(msg).await {
+                    if let Some(job) = job {
+                        jobs.lock().await.fail(session, job, &format!("write failed: {}", e));
+                    }
+                }
             }
         }
+    };
+
+    {
+        let mut guard = clients.lock().await;
+
+        if owned(&mut guard, id, session).is_some() {
+            guard.remove(&id);
+        }
     }
+
+    jobs.lock()
+        .await
+        .fail_session(session, "client disconnected");
+    events.send(Event::Disconnected {
+        id,
+        session,
+        reason,
+    });
 }
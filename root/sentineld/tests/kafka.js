@@ -1,6 +1,8 @@
 const WebSocket = require("ws");
 
-const ws = new WebSocket("ws://127.0.0.1:3737/kafka/ws");
+const ws = new WebSocket(
+  `ws://127.0.0.1:3737/kafka/ws?token=${process.env.SENTINEL_TOKEN ?? ""}`
+);
 
 ws.on("open", () => {
   console.log("Connected");
@@ -1,6 +1,6 @@
 const net = require("net");
 
-const SOCKET_PATH = "/tmp/sentinel.sock";
+const SOCKET_PATH = "/run/sentinel/sentinel.sock";
 
 // Get CLI arguments (skip node + file)
 const command = process.argv.slice(2).join(" ");